@@ -1,13 +1,391 @@
-use reqwest::{ClientBuilder, header};
-use chrono::{DateTime, FixedOffset};
+use reqwest::{ClientBuilder, header, StatusCode};
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use chrono_tz::America::New_York;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
 use std::env;
-use serde::Deserialize;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::DeserializeOwned;
 
-fn load_env_var(key: &str) -> String {
+pub static DEFAULT_API_BASE_URL: &str = "https://api.alpaca.markets";
+pub static DEFAULT_DATA_BASE_URL: &str = "https://data.alpaca.markets";
+// request quota of the free plan. set RVAT_REQUESTS_PER_MINUTE when your
+// plan's is higher, e.g. 10000 on the paid plan.
+pub static FREE_REQUESTS_PER_MINUTE: u32 = 200;
+
+fn load_env_var(key: &str) -> Result<String, AlpacaClientError> {
+    env::var(key).map_err(|e| AlpacaClientError::Config(format!("couldn't interpret {}: {}", key, e)))
+}
+
+// base url from the environment, or `default` when unset. lets the client be
+// pointed at a local stand-in for the alpaca api.
+pub(crate) fn load_base_url(key: &str, default: &str) -> String {
     match env::var(key) {
-        Ok(val) => val,
-        Err(e) => panic!("couldn't interpret {}: {}", key, e),
+        Ok(val) if !val.is_empty() => val.trim_end_matches('/').to_string(),
+        _ => default.to_string(),
+    }
+}
+
+// trading api host, e.g. calendar and assets. override with APCA_API_BASE_URL
+pub fn api_base_url() -> String {
+    load_base_url("APCA_API_BASE_URL", DEFAULT_API_BASE_URL)
+}
+
+// market data host, e.g. bars. override with APCA_DATA_BASE_URL
+pub fn data_base_url() -> String {
+    load_base_url("APCA_DATA_BASE_URL", DEFAULT_DATA_BASE_URL)
+}
+
+// which trades the market data is built from. sip is every US exchange and
+// needs a paid plan, iex is the one exchange the free plan sees and otc is
+// over the counter names. volumes differ wildly between them, so never mix
+// feeds in one comparison.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Feed {
+    #[default]
+    Sip,
+    Iex,
+    Otc,
+}
+
+impl Feed {
+    // the value of the `feed` query parameter
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feed::Sip => "sip",
+            Feed::Iex => "iex",
+            Feed::Otc => "otc",
+        }
+    }
+
+    // APCA_DATA_FEED, or sip when unset
+    pub fn from_env() -> Result<Feed, AlpacaClientError> {
+        match env::var("APCA_DATA_FEED") {
+            Ok(val) if !val.is_empty() => val.parse(),
+            _ => Ok(Feed::default()),
+        }
+    }
+
+    // the quota to assume when RVAT_REQUESTS_PER_MINUTE isn't set. the free
+    // plan can read historical sip bars too, so no feed says which plan the
+    // account is on and every one gets the free quota.
+    pub fn default_requests_per_minute(&self) -> u32 {
+        FREE_REQUESTS_PER_MINUTE
+    }
+}
+
+impl fmt::Display for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str().to_uppercase())
+    }
+}
+
+impl FromStr for Feed {
+    type Err = AlpacaClientError;
+
+    fn from_str(s: &str) -> Result<Feed, AlpacaClientError> {
+        match s.to_lowercase().as_str() {
+            "sip" => Ok(Feed::Sip),
+            "iex" => Ok(Feed::Iex),
+            "otc" => Ok(Feed::Otc),
+            _ => Err(AlpacaClientError::Config(format!("unknown data feed {}, expected sip, iex or otc", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeframeUnit {
+    Min,
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl TimeframeUnit {
+    // the unit as it's written in the `timeframe` query value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeframeUnit::Min => "Min",
+            TimeframeUnit::Hour => "Hour",
+            TimeframeUnit::Day => "Day",
+            TimeframeUnit::Week => "Week",
+            TimeframeUnit::Month => "Month",
+        }
+    }
+
+    // amounts alpaca accepts for each unit
+    fn allows(&self, amount: u32) -> bool {
+        match self {
+            TimeframeUnit::Min => (1..=59).contains(&amount),
+            TimeframeUnit::Hour => (1..=23).contains(&amount),
+            TimeframeUnit::Day | TimeframeUnit::Week => amount == 1,
+            TimeframeUnit::Month => [1, 2, 3, 4, 6, 12].contains(&amount),
+        }
+    }
+}
+
+// the width of each bar. anything other than the common sizes is built with
+// Timeframe::custom, which rejects amounts alpaca doesn't support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeframe {
+    OneMin,
+    FiveMin,
+    FifteenMin,
+    OneHour,
+    OneDay,
+    Custom(CustomTimeframe),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomTimeframe {
+    amount: u32,
+    unit: TimeframeUnit,
+}
+
+impl Timeframe {
+    pub fn custom(amount: u32, unit: TimeframeUnit) -> Result<Timeframe, AlpacaClientError> {
+        if !unit.allows(amount) {
+            return Err(AlpacaClientError::Config(format!("{} {} is not a valid timeframe", amount, unit.as_str())));
+        }
+        Ok(Timeframe::Custom(CustomTimeframe { amount, unit }))
+    }
+
+    pub fn amount(&self) -> u32 {
+        match self {
+            Timeframe::OneMin | Timeframe::OneHour | Timeframe::OneDay => 1,
+            Timeframe::FiveMin => 5,
+            Timeframe::FifteenMin => 15,
+            Timeframe::Custom(custom) => custom.amount,
+        }
+    }
+
+    pub fn unit(&self) -> TimeframeUnit {
+        match self {
+            Timeframe::OneMin | Timeframe::FiveMin | Timeframe::FifteenMin => TimeframeUnit::Min,
+            Timeframe::OneHour => TimeframeUnit::Hour,
+            Timeframe::OneDay => TimeframeUnit::Day,
+            Timeframe::Custom(custom) => custom.unit,
+        }
+    }
+}
+
+// the `timeframe` query value, e.g. "1Min" or "4Hour"
+impl fmt::Display for Timeframe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.amount(), self.unit().as_str())
+    }
+}
+
+impl Serialize for Timeframe {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// how prices and volumes before corporate actions are adjusted
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Adjustment {
+    Raw,
+    Split,
+    Dividend,
+    #[default]
+    All,
+}
+
+// bars per page, 1 to 10000. larger results are paged, not truncated.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit(u16);
+
+impl Limit {
+    pub const MAX: Limit = Limit(10000);
+
+    pub fn new(limit: u16) -> Result<Limit, AlpacaClientError> {
+        if limit == 0 || limit > Limit::MAX.0 {
+            return Err(AlpacaClientError::Config(format!("limit must be between 1 and {}, got {}", Limit::MAX.0, limit)));
+        }
+        Ok(Limit(limit))
+    }
+
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+}
+
+// how failed requests are retried. delays grow exponentially from
+// `base_delay` up to `max_delay` with jitter, a 429 waits as long as the
+// server asks, and no request keeps retrying past `max_elapsed` in total.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_elapsed: Duration::from_secs(60),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse::<T>().ok()).unwrap_or(default)
+}
+
+impl RetryPolicy {
+    // the default policy, overridden by RVAT_MAX_RETRIES, RVAT_RETRY_BASE_MS,
+    // RVAT_RETRY_MAX_DELAY_MS and RVAT_RETRY_MAX_ELAPSED_MS when set
+    pub fn from_env() -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            max_retries: env_or("RVAT_MAX_RETRIES", default.max_retries),
+            base_delay: Duration::from_millis(env_or("RVAT_RETRY_BASE_MS", default.base_delay.as_millis() as u64)),
+            max_delay: Duration::from_millis(env_or("RVAT_RETRY_MAX_DELAY_MS", default.max_delay.as_millis() as u64)),
+            max_elapsed: Duration::from_millis(env_or("RVAT_RETRY_MAX_ELAPSED_MS", default.max_elapsed.as_millis() as u64)),
+        }
+    }
+
+    // delay before retry number `attempt` (0 based): half of the exponential
+    // step plus a random share of the other half, so threads that failed
+    // together don't all come back at the same moment
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let step = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        step / 2 + step.mul_f64(jitter / 2.0)
+    }
+}
+
+// token bucket. tokens refill continuously at the quota's rate and each
+// request takes one. when the bucket is empty a caller reserves the next
+// token and sleeps until it is due, so waiting threads are served in order.
+pub struct RateLimiter {
+    capacity: f64,
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    // `capacity` is the largest burst allowed after a quiet spell
+    pub fn new(per_second: f64, capacity: u32) -> RateLimiter {
+        assert!(per_second > 0.0, "Rate must be greater than 0");
+        let capacity = capacity.max(1) as f64;
+        RateLimiter {
+            capacity,
+            per_second,
+            bucket: Mutex::new(Bucket { tokens: capacity, refilled_at: Instant::now() }),
+        }
+    }
+
+    // bursts are limited to one second's worth of requests so a full bucket
+    // can't spend most of a minute's quota at once
+    pub fn per_minute(requests: u32) -> RateLimiter {
+        let requests = requests.max(1);
+        RateLimiter::new(requests as f64 / 60.0, requests.div_ceil(60))
+    }
+
+    // take a token, returning how long to wait before it may be used
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+        bucket.refilled_at = now;
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.per_second)
+        }
+    }
+
+    // wait until a request may be sent
+    pub async fn acquire(&self) {
+        let wait = self.reserve();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// run `request` until it succeeds, fails with an error that isn't worth
+// retrying, or the policy runs out of attempts or time
+pub async fn with_retry<T, F, Fut>(policy: &RetryPolicy, mut request: F) -> Result<T, AlpacaClientError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AlpacaClientError>>,
+{
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let error = match request().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if attempt >= policy.max_retries || !error.is_retryable() {
+            return Err(error);
+        }
+        let delay = match &error {
+            AlpacaClientError::RateLimited { retry_after: Some(wait), .. } => *wait,
+            _ => policy.backoff(attempt),
+        };
+        if started.elapsed() + delay > policy.max_elapsed {
+            return Err(error);
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// how long a 429 asks us to wait, from Retry-After (seconds) or else
+// X-RateLimit-Reset (unix time the quota refills)
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    let value = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+    if let Some(seconds) = value("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+    let reset = value("x-ratelimit-reset")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now)))
+}
+
+fn auth_headers() -> Result<header::HeaderMap, AlpacaClientError> {
+    let mut headers = header::HeaderMap::new();
+    for (name, key) in [("APCA-API-KEY-ID", "APCA_API_KEY_ID"), ("APCA-API-SECRET-KEY", "APCA_API_SECRET_KEY")] {
+        let value = header::HeaderValue::from_str(&load_env_var(key)?)
+            .map_err(|e| AlpacaClientError::Config(format!("invalid {}: {}", key, e)))?;
+        headers.insert(name, value);
     }
+    Ok(headers)
+}
+
+// alpaca sends `null` rather than `[]` or `{}` when there is nothing to return
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
 }
 
   //{
@@ -29,14 +407,176 @@ pub struct Calendar {
     pub settlement_date: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// a record with a missing field or a value of the wrong type (e.g. a
+// fractional or negative volume) fails to deserialize rather than being
+// carried around as an untyped value
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Bar {
     pub t:DateTime<FixedOffset>,
-    pub o:serde_json::Value,
-    pub h:serde_json::Value,
-    pub l:serde_json::Value,
-    pub c:serde_json::Value,
-    pub v:serde_json::Value
+    pub o:f64,
+    pub h:f64,
+    pub l:f64,
+    pub c:f64,
+    pub v:u64,
+    // trade count
+    #[serde(default)]
+    pub n:u64,
+    // volume weighted average price
+    #[serde(default)]
+    pub vw:f64,
+}
+
+// /v2/clock. next_open and next_close are the regular session's bell times.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Clock {
+    pub timestamp: DateTime<FixedOffset>,
+    pub is_open: bool,
+    pub next_open: DateTime<FixedOffset>,
+    pub next_close: DateTime<FixedOffset>,
+}
+
+// a single trade, e.g. the latest one for a symbol
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub t:DateTime<FixedOffset>,
+    // exchange
+    #[serde(default)]
+    pub x:String,
+    // price and size
+    pub p:f64,
+    pub s:u64,
+    // conditions
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub c:Vec<String>,
+    // tape
+    #[serde(default)]
+    pub z:String,
+}
+
+// the best bid and ask, e.g. the latest ones for a symbol
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub t:DateTime<FixedOffset>,
+    // ask exchange, price and size
+    #[serde(default)]
+    pub ax:String,
+    pub ap:f64,
+    pub r#as:u64,
+    // bid exchange, price and size
+    #[serde(default)]
+    pub bx:String,
+    pub bp:f64,
+    pub bs:u64,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub c:Vec<String>,
+    #[serde(default)]
+    pub z:String,
+}
+
+impl Quote {
+    pub fn spread(&self) -> f64 {
+        self.ap - self.bp
+    }
+}
+
+// everything alpaca knows about a symbol right now. any part can be missing,
+// e.g. no minute bar yet before the first trade of the day.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub latest_trade:Option<Trade>,
+    pub latest_quote:Option<Quote>,
+    pub minute_bar:Option<Bar>,
+    pub daily_bar:Option<Bar>,
+    pub prev_daily_bar:Option<Bar>,
+}
+
+impl Snapshot {
+    pub fn last_price(&self) -> Option<f64> {
+        self.latest_trade.as_ref().map(|trade| trade.p)
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        self.latest_quote.as_ref().map(Quote::spread)
+    }
+
+    // the daily bar for `session`, None until it has printed at the open
+    pub fn session_bar(&self, session:NaiveDate) -> Option<&Bar> {
+        self.daily_bar.as_ref().filter(|bar| bar_date(bar) == session)
+    }
+
+    // the close of the session before `session`. before the open the daily
+    // bar is still yesterday's, after it the previous daily bar is.
+    pub fn previous_close(&self, session:NaiveDate) -> Option<f64> {
+        match &self.daily_bar {
+            Some(bar) if bar_date(bar) < session => Some(bar.c),
+            _ => self.prev_daily_bar.as_ref().map(|bar| bar.c),
+        }
+    }
+}
+
+// daily bars are stamped at midnight in New York
+fn bar_date(bar:&Bar) -> NaiveDate {
+    bar.t.with_timezone(&New_York).date_naive()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LatestTradeResponse {
+    trade:Trade,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LatestQuoteResponse {
+    quote:Quote,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LatestTradesResponse {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    trades:HashMap<String, Trade>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetClass {
+    UsEquity,
+    UsOption,
+    Crypto,
+    #[serde(other)]
+    Other,
+}
+
+// inactive assets have been delisted
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetStatus {
+    Active,
+    Inactive,
+    #[serde(other)]
+    Other,
+}
+
+// an asset from /v2/assets. `tradable` is false while alpaca has trading in
+// the name halted.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Asset {
+    pub id: String,
+    pub class: AssetClass,
+    // e.g. "NASDAQ", "NYSE", "ARCA", "OTC"
+    pub exchange: String,
+    pub symbol: String,
+    #[serde(default)]
+    pub name: String,
+    pub status: AssetStatus,
+    pub tradable: bool,
+    #[serde(default)]
+    pub marginable: bool,
+    #[serde(default)]
+    pub shortable: bool,
+    #[serde(default)]
+    pub easy_to_borrow: bool,
+    #[serde(default)]
+    pub fractionable: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -47,7 +587,10 @@ pub struct ErrorResponse {
 #[derive(Deserialize, Debug, Clone)]
 pub struct BarResponse {
     //symbol: String,
+    #[serde(default, deserialize_with = "null_as_empty")]
     bars: Vec<Bar>,
+    #[serde(default)]
+    next_page_token: Option<String>,
 }
 
 impl BarResponse {
@@ -56,114 +599,820 @@ impl BarResponse {
     }
 }
 
+// /v2/stocks/bars, bars keyed by symbol
+#[derive(Deserialize, Debug, Clone)]
+struct MultiBarResponse {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    bars: HashMap<String, Vec<Bar>>,
+    #[serde(default)]
+    next_page_token: Option<String>,
+}
+
+// query for both bars endpoints
+#[derive(Serialize)]
+struct BarsQuery<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbols: Option<&'a str>,
+    timeframe: Timeframe,
+    start: &'a str,
+    end: &'a str,
+    limit: Limit,
+    adjustment: Adjustment,
+    feed: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page_token: Option<&'a str>,
+}
+
+// most symbols the multi symbol endpoints are asked for in one request, keeps
+// the query string a sensible length
+pub static MAX_SYMBOLS_PER_REQUEST: usize = 100;
+
 
 #[derive(Debug)]
 pub enum AlpacaClientError {
-    ReqwestError(reqwest::Error),
+    // the request never got a response: dns, connect, tls, timeout
+    Transport(reqwest::Error),
+    // any other non 2xx status, with the message from the ErrorResponse body
+    Http { status: u16, message: String },
+    // 429 Too Many Requests, with how long the server asked us to wait
+    RateLimited { message: String, retry_after: Option<Duration> },
+    // 401 or 403, bad keys or a plan without access to the data
+    Unauthorized { status: u16, message: String },
+    // a 2xx response whose body didn't match the expected shape
+    Decode(serde_json::Error),
+    // missing or unusable api keys in the environment
+    Config(String),
+}
+
+impl fmt::Display for AlpacaClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "request failed: {}", e),
+            Self::Http { status, message } => write!(f, "http {}: {}", status, message),
+            Self::RateLimited { message, .. } => write!(f, "rate limited: {}", message),
+            Self::Unauthorized { status, message } => write!(f, "unauthorized ({}): {}", status, message),
+            Self::Decode(e) => write!(f, "couldn't decode response: {}", e),
+            Self::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AlpacaClientError {}
+
+impl AlpacaClientError {
+    // network blips, rate limiting and server side errors can succeed on a
+    // second try, bad keys and bad responses won't
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Transport(_) | Self::RateLimited { .. } => true,
+            Self::Http { status, .. } => *status >= 500,
+            Self::Unauthorized { .. } | Self::Decode(_) | Self::Config(_) => false,
+        }
+    }
 }
 
 impl From<reqwest::Error> for AlpacaClientError {
     fn from(e: reqwest::Error) -> Self {
-        Self::ReqwestError(e)
+        Self::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for AlpacaClientError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Decode(e)
+    }
+}
+
+// turn a response into T, or the error variant matching its status
+async fn decode_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, AlpacaClientError> {
+    let status = response.status();
+    let wait = retry_after(response.headers());
+    let body = response.text().await?;
+    if status.is_success() {
+        return Ok(serde_json::from_str(&body)?);
     }
+    let message = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(error) => error.message,
+        Err(_) => body,
+    };
+    Err(match status {
+        StatusCode::TOO_MANY_REQUESTS => AlpacaClientError::RateLimited { message, retry_after: wait },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AlpacaClientError::Unauthorized {
+            status: status.as_u16(),
+            message,
+        },
+        _ => AlpacaClientError::Http { status: status.as_u16(), message },
+    })
 }
 
-// async alpaca client
+// async alpaca client. cloning is cheap and every clone shares the same
+// connection pool and rate limiter, so make one and hand clones to each task.
+#[derive(Clone)]
 pub struct AlpacaClient {
     pub client:reqwest::Client,
+    pub api_base_url:String,
+    pub data_base_url:String,
+    // sent with every market data request
+    pub feed:Feed,
+    pub retry:RetryPolicy,
+    pub limiter:Arc<RateLimiter>,
+}
+
+fn check_range(start:DateTime<FixedOffset>, end:DateTime<FixedOffset>) -> Result<(), AlpacaClientError> {
+    if start >= end {
+        return Err(AlpacaClientError::Config(format!("start {} must be before end {}", start, end)));
+    }
+    Ok(())
+}
+
+// the multi symbol endpoints take between 1 and MAX_SYMBOLS_PER_REQUEST symbols
+fn check_symbols(symbols:&[String]) -> Result<(), AlpacaClientError> {
+    if symbols.is_empty() || symbols.len() > MAX_SYMBOLS_PER_REQUEST {
+        return Err(AlpacaClientError::Config(format!("{} symbols given, expected 1 to {}", symbols.len(), MAX_SYMBOLS_PER_REQUEST)));
+    }
+    Ok(())
 }
 
 impl AlpacaClient {
+    // keys, hosts, feed, retry policy and request quota all come from the environment
     pub fn new() -> Result<AlpacaClient, AlpacaClientError> {
-        let mut headers = header::HeaderMap::new();
-        headers.insert("APCA-API-KEY-ID", header::HeaderValue::from_str(&load_env_var("APCA_API_KEY_ID")).unwrap());
-        headers.insert("APCA-API-SECRET-KEY", header::HeaderValue::from_str(&load_env_var("APCA_API_SECRET_KEY")).unwrap());
+        AlpacaClient::with_feed(Feed::from_env()?)
+    }
+
+    // like new, but for `feed` whatever APCA_DATA_FEED says
+    pub fn with_feed(feed: Feed) -> Result<AlpacaClient, AlpacaClientError> {
         let client = ClientBuilder::new()
-            .default_headers(headers)
+            .default_headers(auth_headers()?)
             .build()?;
-        Ok(AlpacaClient { client })
+        Ok(AlpacaClient {
+            client,
+            api_base_url: api_base_url(),
+            data_base_url: data_base_url(),
+            feed,
+            retry: RetryPolicy::from_env(),
+            limiter: Arc::new(RateLimiter::per_minute(
+                env_or("RVAT_REQUESTS_PER_MINUTE", feed.default_requests_per_minute()))),
+        })
     }
-}
 
-pub fn get_calendar(start:DateTime<FixedOffset>, end:DateTime<FixedOffset>) -> Vec<Calendar>{
-    assert!(start < end, "Start date must be before end date");
-    let mut headers = header::HeaderMap::new();
-    headers.insert("APCA-API-KEY-ID", 
-                   header::HeaderValue::from_str(&load_env_var("APCA_API_KEY_ID")).unwrap());
-    headers.insert("APCA-API-SECRET-KEY", 
-                   header::HeaderValue::from_str(&load_env_var("APCA_API_SECRET_KEY")).unwrap());
-    let response = reqwest::blocking::Client::new()
-        .get("https://api.alpaca.markets/v2/calendar")
-        .query(&[("start", start.to_rfc3339()), ("end", end.to_rfc3339())])
-        .headers(headers)
-        .send();
-    match response {
-        Ok(resp) => {
-            match resp.json::<Vec<Calendar>>() {
-                Ok(mut calendar) => {
-                    calendar.reverse();
-                    calendar
-                },
-                Err(e) => {
-                    panic!("Error parsing calendar response: {}", e)
-                }
+    // GET `url`, waiting on the rate limiter before every attempt and
+    // retrying according to the policy
+    async fn get<T, Q>(&self, url: &str, query: &Q) -> Result<T, AlpacaClientError>
+    where
+        T: DeserializeOwned,
+        Q: Serialize + ?Sized,
+    {
+        with_retry(&self.retry, || async {
+            self.limiter.acquire().await;
+            let response = self.client.get(url).query(query).send().await?;
+            decode_response(response).await
+        }).await
+    }
+
+    // trading days between start and end, most recent first
+    pub async fn get_calendar(&self, start:DateTime<FixedOffset>, end:DateTime<FixedOffset>) -> Result<Vec<Calendar>, AlpacaClientError> {
+        check_range(start, end)?;
+        let url = format!("{}/v2/calendar", self.api_base_url);
+        let mut calendar:Vec<Calendar> = self.get(&url, &[("start", start.to_rfc3339()), ("end", end.to_rfc3339())]).await?;
+        calendar.reverse();
+        Ok(calendar)
+    }
+
+    pub async fn get_clock(&self) -> Result<Clock, AlpacaClientError> {
+        let url = format!("{}/v2/clock", self.api_base_url);
+        self.get(&url, &()).await
+    }
+
+    pub async fn get_assets(&self) -> Result<Vec<Asset>, AlpacaClientError> {
+        let url = format!("{}/v2/assets", self.api_base_url);
+        self.get(&url, &[("asset_class", "us_equity")]).await
+    }
+
+    pub async fn get_latest_trade(&self, symbol:&str) -> Result<Trade, AlpacaClientError> {
+        let url = format!("{}/v2/stocks/{symbol}/trades/latest", self.data_base_url);
+        let resp:LatestTradeResponse = self.get(&url, &[("feed", self.feed.as_str())]).await?;
+        Ok(resp.trade)
+    }
+
+    pub async fn get_latest_quote(&self, symbol:&str) -> Result<Quote, AlpacaClientError> {
+        let url = format!("{}/v2/stocks/{symbol}/quotes/latest", self.data_base_url);
+        let resp:LatestQuoteResponse = self.get(&url, &[("feed", self.feed.as_str())]).await?;
+        Ok(resp.quote)
+    }
+
+    // latest trade for many symbols in one request. symbols alpaca has no
+    // trade for are left out of the map.
+    pub async fn get_latest_trades(&self, symbols:&[String]) -> Result<HashMap<String, Trade>, AlpacaClientError> {
+        check_symbols(symbols)?;
+        let url = format!("{}/v2/stocks/trades/latest", self.data_base_url);
+        let resp:LatestTradesResponse = self.get(&url, &[("symbols", symbols.join(",")),
+                                                         ("feed", self.feed.as_str().to_string())]).await?;
+        Ok(resp.trades)
+    }
+
+    pub async fn get_snapshot(&self, symbol:&str) -> Result<Snapshot, AlpacaClientError> {
+        let url = format!("{}/v2/stocks/{symbol}/snapshot", self.data_base_url);
+        self.get(&url, &[("feed", self.feed.as_str())]).await
+    }
+
+    // snapshots for many symbols in one request, keyed by symbol. unknown
+    // symbols are left out of the map.
+    pub async fn get_snapshots(&self, symbols:&[String]) -> Result<HashMap<String, Snapshot>, AlpacaClientError> {
+        check_symbols(symbols)?;
+        let url = format!("{}/v2/stocks/snapshots", self.data_base_url);
+        let snapshots:HashMap<String, Option<Snapshot>> = self.get(&url, &[("symbols", symbols.join(",")),
+                                                                          ("feed", self.feed.as_str().to_string())]).await?;
+        Ok(snapshots.into_iter()
+            .filter_map(|(symbol, snapshot)| Some((symbol, snapshot?)))
+            .collect())
+    }
+
+    // follows next_page_token until every page is fetched and returns the bars
+    // merged into a single response. a symbol with no trades is an Ok with no bars.
+    pub async fn get_bars(&self, ticker:&str, timeframe:Timeframe, start:DateTime<FixedOffset>, end:DateTime<FixedOffset>, limit:Limit, adjustment:Adjustment) -> Result<BarResponse, AlpacaClientError> {
+        check_range(start, end)?;
+        if ticker.is_empty() {
+            return Err(AlpacaClientError::Config("a ticker must be provided".to_string()));
+        }
+        let url = format!("{}/v2/stocks/{ticker}/bars", self.data_base_url);
+        let start = start.to_rfc3339();
+        let end = end.to_rfc3339();
+        let mut resp = BarResponse {
+            bars: Vec::new(),
+            next_page_token: None,
+        };
+        let mut page_token:Option<String> = None;
+        loop {
+            let query = BarsQuery {
+                symbols: None,
+                timeframe,
+                start: &start,
+                end: &end,
+                limit,
+                adjustment,
+                feed: self.feed.as_str(),
+                page_token: page_token.as_deref(),
+            };
+            let mut page:BarResponse = self.get(&url, &query).await?;
+            resp.bars.append(&mut page.bars);
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
             }
-        },
-        Err(e) => panic!("Error getting calendar: {}", e)
+        }
+        resp.bars.reverse(); // reverse the bars so they start with most recent
+        Ok(resp)
+    }
+
+    // bars for many symbols in one request, following next_page_token. like
+    // get_bars each symbol's bars start with the most recent. symbols with no
+    // trades in the window are left out of the map.
+    pub async fn get_multi_bars(&self, symbols:&[String], timeframe:Timeframe, start:DateTime<FixedOffset>, end:DateTime<FixedOffset>, limit:Limit, adjustment:Adjustment) -> Result<HashMap<String, Vec<Bar>>, AlpacaClientError> {
+        check_range(start, end)?;
+        check_symbols(symbols)?;
+        let url = format!("{}/v2/stocks/bars", self.data_base_url);
+        let symbols = symbols.join(",");
+        let start = start.to_rfc3339();
+        let end = end.to_rfc3339();
+        let mut bars:HashMap<String, Vec<Bar>> = HashMap::new();
+        let mut page_token:Option<String> = None;
+        loop {
+            let query = BarsQuery {
+                symbols: Some(&symbols),
+                timeframe,
+                start: &start,
+                end: &end,
+                limit,
+                adjustment,
+                feed: self.feed.as_str(),
+                page_token: page_token.as_deref(),
+            };
+            let page:MultiBarResponse = self.get(&url, &query).await?;
+            // a symbol's bars can be split across the end of one page and
+            // the start of the next
+            for (symbol, mut symbol_bars) in page.bars {
+                bars.entry(symbol).or_default().append(&mut symbol_bars);
+            }
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        for symbol_bars in bars.values_mut() {
+            symbol_bars.reverse();
+        }
+        Ok(bars)
     }
 }
 
-pub fn get_bars(ticker:&str, timeframe:&str, start:DateTime<FixedOffset>, end:DateTime<FixedOffset>, limit:&str) -> BarResponse {
-    assert!(start < end, "Start date must be before end date");
-    assert!(limit.parse::<i32>().unwrap() <= 10000, "Limit must be less than 10000");
-    assert!(limit.parse::<i32>().unwrap() > 0, "Limit must be greater than 0");
-    assert!(ticker.len() > 0, "Ticker must be provided");
-    assert!(timeframe.len() > 0, "Timeframe must be provided");
-    let mut headers = header::HeaderMap::new();
-    headers.insert("APCA-API-KEY-ID", header::HeaderValue::from_str(&load_env_var("APCA_API_KEY_ID")).unwrap());
-    headers.insert("APCA-API-SECRET-KEY", header::HeaderValue::from_str(&load_env_var("APCA_API_SECRET_KEY")).unwrap());
-    let mut resp = match reqwest::blocking::Client::new()
-        .get(format!("https://data.alpaca.markets/v2/stocks/{ticker}/bars"))
-        .query(&[   ("limit", limit), 
-                    ("timeframe", timeframe), 
-                    ("adjustment", "all"),
-                    ("start", start.to_rfc3339().as_str()),
-                    ("end", end.to_rfc3339().as_str())
-                ])
-        .headers(headers)
-        .send() {
-            Ok(response) => {
-                match response.json::<BarResponse>() {
-                    Ok(resp) => resp,
-                    Err(_) => {
-                        // Server 500's are inevitable, so we just return an empty response
-                        BarResponse {
-                            bars: Vec::new()
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::test_support::{client, MOCK_URL};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    static FLAKY_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static THROTTLED_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Reply {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+    }
+
+    fn reply(status: u16, body: &str) -> Reply {
+        Reply { status, headers: Vec::new(), body: body.to_string() }
+    }
+
+    impl Reply {
+        fn header(mut self, name: &'static str, value: &str) -> Reply {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+    }
+
+    // canned responses for the local stand-in, keyed on the request path
+    fn route(path: &str) -> Reply {
+        if path.starts_with("/v2/calendar") {
+            return reply(200, r#"[
+                {"date":"2023-01-10","open":"09:30","close":"16:00","session_open":"0400","session_close":"2000","settlement_date":"2023-01-12"},
+                {"date":"2023-01-11","open":"09:30","close":"16:00","session_open":"0400","session_close":"2000","settlement_date":"2023-01-13"}
+            ]"#);
+        }
+        if path.starts_with("/v2/clock") {
+            return reply(200, r#"{"timestamp":"2023-01-11T08:15:00.123456789-05:00","is_open":false,"next_open":"2023-01-11T09:30:00-05:00","next_close":"2023-01-11T16:00:00-05:00"}"#);
+        }
+        if path.starts_with("/v2/assets") {
+            return reply(200, r#"[
+                {"id":"b0b6dd9d-8b9b-48a9-ba46-b9d54906e415","class":"us_equity","exchange":"NASDAQ","symbol":"AAPL","name":"Apple Inc. Common Stock","status":"active","tradable":true,"marginable":true,"shortable":true,"easy_to_borrow":true,"fractionable":true,"maintenance_margin_requirement":30,"attributes":[]},
+                {"id":"0b7a7a5e-6b9b-4a6e-9c1c-1a1f6a2c7b1d","class":"us_equity","exchange":"OTC","symbol":"GONE","name":"Gone Corp","status":"inactive","tradable":false,"marginable":false,"shortable":false,"easy_to_borrow":false,"fractionable":false}
+            ]"#);
+        }
+        if path.starts_with("/v2/stocks/PAGED/bars") {
+            if path.contains("page_token=page2") {
+                return reply(200, r#"{"symbol":"PAGED","next_page_token":null,"bars":[
+                    {"t":"2023-01-11T09:02:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":300}
+                ]}"#);
+            }
+            return reply(200, r#"{"symbol":"PAGED","next_page_token":"page2","bars":[
+                {"t":"2023-01-11T09:00:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":100},
+                {"t":"2023-01-11T09:01:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":200}
+            ]}"#);
+        }
+        if path.starts_with("/v2/stocks/bars?") {
+            assert!(path.contains("symbols=AAPL%2CMSFT%2CQUIET"), "unexpected symbols in {}", path);
+            if path.contains("page_token=page2") {
+                return reply(200, r#"{"next_page_token":null,"bars":{
+                    "MSFT":[{"t":"2023-01-11T09:01:00Z","o":2.0,"h":2.0,"l":2.0,"c":2.0,"v":40}]
+                }}"#);
+            }
+            return reply(200, r#"{"next_page_token":"page2","bars":{
+                "AAPL":[{"t":"2023-01-11T09:00:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":10},
+                        {"t":"2023-01-11T09:01:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":20}],
+                "MSFT":[{"t":"2023-01-11T09:00:00Z","o":2.0,"h":2.0,"l":2.0,"c":2.0,"v":30}]
+            }}"#);
+        }
+        if path.starts_with("/v2/stocks/QUIET/bars") {
+            return reply(200, r#"{"symbol":"QUIET","next_page_token":null,"bars":null}"#);
+        }
+        if path.starts_with("/v2/stocks/BROKEN/bars") {
+            return reply(500, r#"{"code":50010000,"message":"internal server error occurred"}"#);
+        }
+        if path.starts_with("/v2/stocks/LIMITED/bars") {
+            return reply(429, r#"{"message":"too many requests."}"#);
+        }
+        if path.starts_with("/v2/stocks/DENIED/bars") {
+            return reply(403, r#"{"message":"subscription does not permit querying recent SIP data"}"#);
+        }
+        if path.starts_with("/v2/stocks/FLAKY/bars") {
+            if FLAKY_CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+                return reply(503, r#"{"message":"service unavailable"}"#);
+            }
+            return reply(200, r#"{"symbol":"FLAKY","next_page_token":null,"bars":[
+                {"t":"2023-01-11T09:00:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":100}
+            ]}"#);
+        }
+        if path.starts_with("/v2/stocks/THROTTLED/bars") {
+            if THROTTLED_CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+                return reply(429, r#"{"message":"too many requests."}"#).header("Retry-After", "1");
+            }
+            return reply(200, r#"{"symbol":"THROTTLED","next_page_token":null,"bars":[]}"#);
+        }
+        if path.starts_with("/v2/stocks/IEXONLY/bars") {
+            if !path.contains("feed=iex") {
+                return reply(403, r#"{"message":"subscription does not permit querying recent SIP data"}"#);
+            }
+            return reply(200, r#"{"symbol":"IEXONLY","next_page_token":null,"bars":[
+                {"t":"2023-01-11T09:00:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":100}
+            ]}"#);
+        }
+        if path.starts_with("/v2/stocks/QUERY/bars") {
+            for param in ["timeframe=4Hour", "limit=500", "adjustment=split", "feed=sip"] {
+                assert!(path.contains(param), "expected {} in {}", param, path);
+            }
+            return reply(200, r#"{"symbol":"QUERY","next_page_token":null,"bars":[]}"#);
+        }
+        if path.starts_with("/v2/stocks/AAPL/trades/latest") {
+            assert!(path.contains("feed=sip"), "expected the feed in {}", path);
+            return reply(200, r#"{"symbol":"AAPL","trade":{"t":"2023-01-11T15:00:00.123Z","x":"V","p":130.5,"s":100,"c":["@"],"i":52983525029461,"z":"C"}}"#);
+        }
+        if path.starts_with("/v2/stocks/AAPL/quotes/latest") {
+            return reply(200, r#"{"symbol":"AAPL","quote":{"t":"2023-01-11T15:00:00.456Z","ax":"V","ap":130.52,"as":2,"bx":"V","bp":130.48,"bs":3,"c":["R"],"z":"C"}}"#);
+        }
+        if path.starts_with("/v2/stocks/trades/latest?") {
+            return reply(200, r#"{"trades":{
+                "AAPL":{"t":"2023-01-11T15:00:00Z","x":"V","p":130.5,"s":100,"c":null,"z":"C"},
+                "MSFT":{"t":"2023-01-11T15:00:00Z","x":"Q","p":235.1,"s":5,"c":["@","I"],"z":"C"}
+            }}"#);
+        }
+        if path.starts_with("/v2/stocks/snapshots?") {
+            return reply(200, r#"{
+                "AAPL":{
+                    "latestTrade":{"t":"2023-01-11T15:00:00Z","x":"V","p":130.5,"s":100,"c":["@"],"z":"C"},
+                    "latestQuote":{"t":"2023-01-11T15:00:00Z","ax":"V","ap":130.52,"as":2,"bx":"V","bp":130.48,"bs":3,"c":["R"],"z":"C"},
+                    "minuteBar":{"t":"2023-01-11T14:59:00Z","o":130.4,"h":130.6,"l":130.4,"c":130.5,"v":5000,"n":50,"vw":130.5},
+                    "dailyBar":{"t":"2023-01-11T05:00:00Z","o":129.0,"h":131.0,"l":128.5,"c":130.5,"v":1000000,"n":9000,"vw":130.1},
+                    "prevDailyBar":{"t":"2023-01-10T05:00:00Z","o":127.0,"h":129.5,"l":126.8,"c":129.0,"v":1500000,"n":12000,"vw":128.4}
+                },
+                "DELISTED":null
+            }"#);
+        }
+        if path.starts_with("/v2/stocks/GARBAGE/bars") {
+            return reply(200, "<html>not json</html>");
+        }
+        reply(404, r#"{"message":"not found"}"#)
+    }
+
+    // serve `route` over plain http on a random local port, one thread per
+    // connection, and return the base url
+    pub(crate) fn mock_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    loop {
+                        let mut header = String::new();
+                        if reader.read_line(&mut header).unwrap() == 0 || header == "\r\n" {
+                            break;
                         }
                     }
-                }
+                    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+                    let reply = route(path);
+                    let headers:String = reply.headers.iter()
+                        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                        .collect();
+                    let response = format!(
+                        "HTTP/1.1 {} \r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+                        reply.status, reply.body.len(), headers, reply.body);
+                    stream.write_all(response.as_bytes()).unwrap();
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn get_calendar() {
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-12T00:00:00-05:00").unwrap();
+        let start = chrono::DateTime::parse_from_rfc3339("2021-01-10T00:00:00-05:00").unwrap();
+        let calendar = client().get_calendar(start, end).await.unwrap();
+        assert!(!calendar.is_empty(), "Calendar is empty");
+        assert_eq!(calendar[0].date, "2023-01-11", "Calendar should start with the most recent day");
+    }
+
+    #[tokio::test]
+    async fn bad_arguments_are_config_errors() {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        let client = client();
+        let config_error = |result:Result<(), super::AlpacaClientError>| matches!(result, Err(super::AlpacaClientError::Config(_)));
+        assert!(config_error(client.get_calendar(end, start).await.map(|_| ())));
+        assert!(config_error(client.get_bars("AAPL", super::Timeframe::OneMin, end, start, super::Limit::MAX, super::Adjustment::All).await.map(|_| ())));
+        assert!(config_error(client.get_bars("", super::Timeframe::OneMin, start, end, super::Limit::MAX, super::Adjustment::All).await.map(|_| ())));
+        assert!(config_error(client.get_snapshots(&[]).await.map(|_| ())));
+        let too_many:Vec<String> = (0..=super::MAX_SYMBOLS_PER_REQUEST).map(|i| format!("S{}", i)).collect();
+        assert!(config_error(client.get_latest_trades(&too_many).await.map(|_| ())));
+        assert!(config_error(client.get_multi_bars(&too_many, super::Timeframe::OneMin, start, end, super::Limit::MAX, super::Adjustment::All).await.map(|_| ())));
+    }
+
+    #[tokio::test]
+    async fn get_clock() {
+        let clock = client().get_clock().await.unwrap();
+        assert!(!clock.is_open);
+        assert_eq!(clock.next_open.to_rfc3339(), "2023-01-11T09:30:00-05:00");
+        assert!(clock.timestamp < clock.next_open && clock.next_open < clock.next_close);
+    }
+
+    #[tokio::test]
+    async fn get_assets_are_typed() {
+        let assets = client().get_assets().await.unwrap();
+        assert_eq!(assets.len(), 2);
+        assert_eq!(assets[0].class, super::AssetClass::UsEquity);
+        assert_eq!(assets[0].status, super::AssetStatus::Active);
+        assert!(assets[0].shortable && assets[0].easy_to_borrow && assets[0].fractionable);
+        assert_eq!(assets[1].status, super::AssetStatus::Inactive);
+        assert!(!assets[1].tradable);
+    }
+
+    #[test]
+    fn base_urls_come_from_env() {
+        lazy_static::initialize(&MOCK_URL);
+        assert_eq!(super::api_base_url(), *MOCK_URL);
+        assert_eq!(super::data_base_url(), *MOCK_URL);
+        assert_eq!(super::load_base_url("RVAT_TEST_UNSET_URL", super::DEFAULT_DATA_BASE_URL),
+                   super::DEFAULT_DATA_BASE_URL);
+        let client = client();
+        assert_eq!(client.api_base_url, *MOCK_URL);
+        assert_eq!(client.data_base_url, *MOCK_URL);
+    }
+
+    #[tokio::test]
+    async fn get_bars_follows_pagination() {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        let resp = client().get_bars("PAGED", super::Timeframe::OneMin, start, end, super::Limit::new(2).unwrap(), super::Adjustment::All).await.unwrap();
+        let volumes:Vec<u64> = resp.get_bars().iter().map(|b| b.v).collect();
+        assert_eq!(volumes, vec![300, 200, 100], "Every page should be merged, most recent first");
+    }
+
+    #[tokio::test]
+    async fn get_bars_sends_the_feed() {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        let mut client = client();
+        assert_eq!(client.feed, super::Feed::Sip, "Feed should default to sip");
+        match client.get_bars("IEXONLY", super::Timeframe::OneMin, start, end, super::Limit::new(10000).unwrap(), super::Adjustment::All).await {
+            Err(super::AlpacaClientError::Unauthorized { status: 403, .. }) => {},
+            other => panic!("expected a 403 on the sip feed, got {:?}", other.map(|r| r.bars.len())),
+        }
+        client.feed = super::Feed::Iex;
+        let resp = client.get_bars("IEXONLY", super::Timeframe::OneMin, start, end, super::Limit::new(10000).unwrap(), super::Adjustment::All).await.unwrap();
+        assert_eq!(resp.get_bars().len(), 1);
+    }
+
+    #[test]
+    fn feed_parses_and_displays() {
+        assert_eq!("IEX".parse::<super::Feed>().unwrap(), super::Feed::Iex);
+        assert_eq!("otc".parse::<super::Feed>().unwrap(), super::Feed::Otc);
+        assert!("delayed".parse::<super::Feed>().is_err());
+        assert_eq!(super::Feed::Sip.to_string(), "SIP");
+        assert_eq!(super::Feed::Sip.as_str(), "sip");
+    }
+
+    #[test]
+    fn every_feed_defaults_to_the_free_quota() {
+        for feed in [super::Feed::Sip, super::Feed::Iex, super::Feed::Otc] {
+            assert_eq!(feed.default_requests_per_minute(), super::FREE_REQUESTS_PER_MINUTE);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_bars_serializes_typed_parameters() {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        let timeframe = super::Timeframe::custom(4, super::TimeframeUnit::Hour).unwrap();
+        let limit = super::Limit::new(500).unwrap();
+        let resp = client().get_bars("QUERY", timeframe, start, end, limit, super::Adjustment::Split).await.unwrap();
+        assert!(resp.get_bars().is_empty());
+    }
+
+    #[test]
+    fn timeframes_match_alpaca() {
+        use super::{Timeframe, TimeframeUnit};
+        assert_eq!(Timeframe::OneMin.to_string(), "1Min");
+        assert_eq!(Timeframe::FiveMin.to_string(), "5Min");
+        assert_eq!(Timeframe::FifteenMin.to_string(), "15Min");
+        assert_eq!(Timeframe::OneHour.to_string(), "1Hour");
+        assert_eq!(Timeframe::OneDay.to_string(), "1Day");
+        assert_eq!(Timeframe::custom(3, TimeframeUnit::Month).unwrap().to_string(), "3Month");
+        assert_eq!(Timeframe::custom(1, TimeframeUnit::Week).unwrap().to_string(), "1Week");
+        assert!(Timeframe::custom(0, TimeframeUnit::Min).is_err());
+        assert!(Timeframe::custom(60, TimeframeUnit::Min).is_err());
+        assert!(Timeframe::custom(2, TimeframeUnit::Day).is_err());
+        assert!(Timeframe::custom(5, TimeframeUnit::Month).is_err());
+    }
+
+    #[test]
+    fn limit_is_bounded() {
+        assert!(super::Limit::new(0).is_err());
+        assert!(super::Limit::new(10001).is_err());
+        assert_eq!(super::Limit::new(10000).unwrap(), super::Limit::MAX);
+        assert_eq!(super::Limit::new(1).unwrap().get(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_latest_trade_and_quote() {
+        let client = client();
+        let trade = client.get_latest_trade("AAPL").await.unwrap();
+        assert_eq!(trade.p, 130.5);
+        assert_eq!(trade.s, 100);
+        assert_eq!(trade.c, vec!["@"]);
+        let quote = client.get_latest_quote("AAPL").await.unwrap();
+        assert_eq!(quote.r#as, 2);
+        assert!((quote.spread() - 0.04).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn get_latest_trades_by_symbol() {
+        let symbols = vec!["AAPL".to_string(), "MSFT".to_string()];
+        let trades = client().get_latest_trades(&symbols).await.unwrap();
+        assert_eq!(trades["AAPL"].p, 130.5);
+        assert!(trades["AAPL"].c.is_empty(), "null conditions should be empty");
+        assert_eq!(trades["MSFT"].x, "Q");
+    }
+
+    #[tokio::test]
+    async fn get_snapshots_by_symbol() {
+        let symbols = vec!["AAPL".to_string(), "DELISTED".to_string()];
+        let snapshots = client().get_snapshots(&symbols).await.unwrap();
+        assert!(!snapshots.contains_key("DELISTED"), "null snapshots should be left out");
+        let snapshot = &snapshots["AAPL"];
+        assert_eq!(snapshot.last_price(), Some(130.5));
+        assert!((snapshot.spread().unwrap() - 0.04).abs() < 1e-9);
+        let today = chrono::NaiveDate::from_ymd_opt(2023, 1, 11).unwrap();
+        let tomorrow = chrono::NaiveDate::from_ymd_opt(2023, 1, 12).unwrap();
+        assert_eq!(snapshot.session_bar(today).map(|bar| bar.v), Some(1000000));
+        assert_eq!(snapshot.previous_close(today), Some(129.0));
+        // before the next open the daily bar is the previous session
+        assert!(snapshot.session_bar(tomorrow).is_none());
+        assert_eq!(snapshot.previous_close(tomorrow), Some(130.5));
+    }
+
+    #[test]
+    fn bar_fields_are_typed() {
+        let bar:super::Bar = serde_json::from_str(
+            r#"{"t":"2023-01-11T14:30:00Z","o":130.28,"h":130.9,"l":130.2,"c":130.5,"v":1224373,"n":10245,"vw":130.553}"#
+        ).unwrap();
+        assert_eq!(bar.v, 1224373);
+        assert_eq!(bar.n, 10245);
+        assert_eq!(bar.c, 130.5);
+        assert_eq!(bar.vw, 130.553);
+    }
+
+    #[test]
+    fn bar_rejects_bad_records() {
+        let fractional_volume = serde_json::from_str::<super::Bar>(
+            r#"{"t":"2023-01-11T14:30:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":1.5}"#);
+        assert!(fractional_volume.unwrap_err().to_string().contains("expected u64"));
+        let missing_close = serde_json::from_str::<super::Bar>(
+            r#"{"t":"2023-01-11T14:30:00Z","o":1.0,"h":1.0,"l":1.0,"v":100}"#);
+        assert!(missing_close.unwrap_err().to_string().contains("missing field `c`"));
+        let text_price = serde_json::from_str::<super::Bar>(
+            r#"{"t":"2023-01-11T14:30:00Z","o":"1.0","h":1.0,"l":1.0,"c":1.0,"v":100}"#);
+        assert!(text_price.is_err());
+    }
+
+    async fn bars_for(ticker: &str) -> Result<super::BarResponse, super::AlpacaClientError> {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        client().get_bars(ticker, super::Timeframe::OneMin, start, end, super::Limit::new(1000).unwrap(), super::Adjustment::All).await
+    }
+
+    #[tokio::test]
+    async fn get_bars_with_no_trades_is_empty() {
+        assert!(bars_for("QUIET").await.unwrap().get_bars().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_bars_reports_failures() {
+        use super::AlpacaClientError;
+        match bars_for("BROKEN").await {
+            Err(AlpacaClientError::Http { status, message }) => {
+                assert_eq!(status, 500);
+                assert_eq!(message, "internal server error occurred");
             },
-            Err(_) => {
-                BarResponse {
-                    bars: Vec::new()
-                }
+            other => panic!("expected Http error, got {:?}", other),
+        }
+        assert!(matches!(bars_for("LIMITED").await, Err(AlpacaClientError::RateLimited { .. })));
+        assert!(matches!(bars_for("DENIED").await, Err(AlpacaClientError::Unauthorized { status: 403, .. })));
+        assert!(matches!(bars_for("GARBAGE").await, Err(AlpacaClientError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn get_bars_retries_server_errors() {
+        let resp = bars_for("FLAKY").await.unwrap();
+        assert_eq!(resp.get_bars().len(), 1);
+        assert_eq!(FLAKY_CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_bars_waits_for_retry_after() {
+        let started = Instant::now();
+        assert!(bars_for("THROTTLED").await.unwrap().get_bars().is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(1), "Retry-After should be respected");
+        assert_eq!(THROTTLED_CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    fn fast_policy() -> super::RetryPolicy {
+        super::RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            max_elapsed: Duration::from_secs(1),
+        }
+    }
+
+    fn server_error() -> super::AlpacaClientError {
+        super::AlpacaClientError::Http { status: 502, message: "bad gateway".to_string() }
+    }
+
+    #[tokio::test]
+    async fn with_retry_gives_up_after_max_retries() {
+        let mut calls = 0;
+        let result:Result<(), _> = super::with_retry(&fast_policy(), || {
+            calls += 1;
+            async { Err(server_error()) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4, "one attempt plus three retries");
+    }
+
+    #[tokio::test]
+    async fn with_retry_does_not_retry_client_errors() {
+        let mut calls = 0;
+        let result:Result<(), _> = super::with_retry(&fast_policy(), || {
+            calls += 1;
+            async { Err(super::AlpacaClientError::Unauthorized { status: 401, message: "bad key".to_string() }) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn with_retry_caps_total_time() {
+        let mut calls = 0;
+        let started = Instant::now();
+        let result:Result<(), _> = super::with_retry(&fast_policy(), || {
+            calls += 1;
+            async {
+                Err(super::AlpacaClientError::RateLimited {
+                    message: "slow down".to_string(),
+                    retry_after: Some(Duration::from_secs(30)),
+                })
             }
-        };
-    resp.bars.reverse(); // reverse the bars so they start with most recent
-    resp
-}
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1, "a wait past max_elapsed should give up instead");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = super::RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_elapsed: Duration::from_secs(60),
+        };
+        for attempt in 0..10 {
+            let step = Duration::from_millis(100 * 2u64.pow(attempt)).min(Duration::from_millis(1000));
+            let delay = policy.backoff(attempt);
+            assert!(delay >= step / 2 && delay <= step, "attempt {} gave {:?}", attempt, delay);
+        }
+    }
 
-    //#[tokio::test]
     #[test]
-    fn get_calendar() {
-        let end = chrono::DateTime::parse_from_rfc3339("2023-01-12T00:00:00-05:00").unwrap();
-        let start = chrono::DateTime::parse_from_rfc3339("2021-01-10T00:00:00-05:00").unwrap();
-        let calendar = super::get_calendar(start, end);
-        assert!(calendar.len() > 0, "Calendar is empty");
+    fn rate_limiter_allows_a_burst_then_spaces_requests() {
+        let limiter = super::RateLimiter::new(10.0, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(), Duration::ZERO);
+        }
+        let first_wait = limiter.reserve();
+        let second_wait = limiter.reserve();
+        assert!(first_wait > Duration::from_millis(50) && first_wait <= Duration::from_millis(100));
+        assert!(second_wait > Duration::from_millis(150) && second_wait <= Duration::from_millis(200),
+                "queued callers should be served one after another, got {:?}", second_wait);
     }
-}
 
+    #[test]
+    fn rate_limiter_per_minute_burst_is_one_second() {
+        let limiter = super::RateLimiter::per_minute(200);
+        for _ in 0..4 {
+            assert_eq!(limiter.reserve(), Duration::ZERO);
+        }
+        assert!(limiter.reserve() > Duration::ZERO);
+    }
 
+    #[tokio::test]
+    async fn get_multi_bars_merges_pages_by_symbol() {
+        let start = chrono::DateTime::parse_from_rfc3339("2023-01-11T04:00:00-05:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2023-01-11T20:00:00-05:00").unwrap();
+        let symbols = vec!["AAPL".to_string(), "MSFT".to_string(), "QUIET".to_string()];
+        let bars = client().get_multi_bars(&symbols, super::Timeframe::OneMin, start, end, super::Limit::new(3).unwrap(), super::Adjustment::All).await.unwrap();
+        let volumes = |symbol: &str| -> Vec<u64> { bars[symbol].iter().map(|b| b.v).collect() };
+        assert_eq!(volumes("AAPL"), vec![20, 10]);
+        assert_eq!(volumes("MSFT"), vec![40, 30], "a symbol split across pages should be joined");
+        assert!(!bars.contains_key("QUIET"));
+    }
+}
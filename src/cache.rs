@@ -0,0 +1,933 @@
+// builds the cache/<SYMBOL>.bars files of 1 minute bars the scanner uses for
+// reference volumes, and prunes and checks them. this replaces build_cache.js.
+use crate::alpaca::{self, Adjustment, AlpacaClient, AlpacaClientError, Asset, AssetStatus, Bar, Calendar, Feed, Limit, Timeframe};
+use crate::session::{self, SessionError};
+use crate::store::{self, BarFile, History};
+use crate::universe::{self, UniverseFilter};
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use chrono_tz::America::New_York;
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub static TRADING_PERIODS: usize = 21;
+pub static BUILD_WORKERS: usize = 5;
+pub static MANIFEST_FILE: &str = "manifest.json";
+
+pub struct BuildOptions {
+    pub cache_dir: PathBuf,
+    pub trading_periods: usize,
+    // symbols fetched at the same time
+    pub workers: usize,
+    // which assets to cache
+    pub universe: UniverseFilter,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            cache_dir: PathBuf::from("cache"),
+            trading_periods: TRADING_PERIODS,
+            workers: BUILD_WORKERS,
+            universe: UniverseFilter::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(io::Error),
+    Alpaca(AlpacaClientError),
+    // an option that can't be carried out, e.g. keeping no days
+    Config(String),
+    // the calendar has fewer sessions than were asked for
+    Session(SessionError),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "cache io error: {}", e),
+            Self::Alpaca(e) => write!(f, "alpaca error: {}", e),
+            Self::Config(message) => write!(f, "cache config error: {}", message),
+            Self::Session(e) => write!(f, "calendar error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<AlpacaClientError> for CacheError {
+    fn from(e: AlpacaClientError) -> Self {
+        Self::Alpaca(e)
+    }
+}
+
+impl From<SessionError> for CacheError {
+    fn from(e: SessionError) -> Self {
+        Self::Session(e)
+    }
+}
+
+// what the cache holds, written to cache/manifest.json by every build so the
+// scanner can tell at startup whether the cache covers the sessions it needs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub built_at: DateTime<Utc>,
+    // the reference sessions fetched, most recent first
+    pub sessions: Vec<NaiveDate>,
+    pub feed: Feed,
+    pub symbols: usize,
+}
+
+impl Manifest {
+    // None when the cache has no manifest yet
+    pub fn load(cache_dir: &Path) -> io::Result<Option<Manifest>> {
+        let contents = match fs::read_to_string(cache_dir.join(MANIFEST_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn write(&self, cache_dir: &Path) -> io::Result<()> {
+        let path = cache_dir.join(MANIFEST_FILE);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self).map_err(io::Error::other)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    // why this cache can't serve as the reference for `feed` and `needed`
+    // sessions, None when it can
+    pub fn staleness(manifest: Option<&Manifest>, feed: Feed, needed: &[NaiveDate]) -> Option<Staleness> {
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => return Some(Staleness::NoManifest),
+        };
+        if manifest.feed != feed {
+            return Some(Staleness::WrongFeed { cached: manifest.feed, wanted: feed });
+        }
+        let missing: Vec<NaiveDate> = needed.iter().copied().filter(|day| !manifest.sessions.contains(day)).collect();
+        (!missing.is_empty()).then_some(Staleness::MissingSessions(missing))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    NoManifest,
+    WrongFeed { cached: Feed, wanted: Feed },
+    MissingSessions(Vec<NaiveDate>),
+}
+
+impl fmt::Display for Staleness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Staleness::NoManifest => write!(f, "cache has no manifest"),
+            Staleness::WrongFeed { cached, wanted } => write!(f, "cache is from the {} feed, not {}", cached, wanted),
+            Staleness::MissingSessions(days) => match days.as_slice() {
+                [day] => write!(f, "cache is missing {}", day),
+                days => write!(f, "cache is missing {} sessions since {}", days.len(), days.iter().min().unwrap()),
+            },
+        }
+    }
+}
+
+// note `days` in the manifest, keeping sessions an earlier build of the same
+// feed already covered
+fn record(client: &AlpacaClient, options: &BuildOptions, days: &[Calendar], symbols: usize) -> io::Result<()> {
+    let mut sessions: Vec<NaiveDate> = days.iter()
+        .filter_map(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok())
+        .collect();
+    if let Ok(Some(previous)) = Manifest::load(&options.cache_dir) {
+        if previous.feed == client.feed {
+            sessions.extend(previous.sessions);
+        }
+    }
+    sessions.sort_by_key(|day| std::cmp::Reverse(*day));
+    sessions.dedup();
+    Manifest {
+        built_at: Utc::now(),
+        sessions,
+        feed: client.feed,
+        symbols,
+    }.write(&options.cache_dir)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BuildSummary {
+    pub symbols: usize,
+    pub written: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    // sessions some symbol's fetch failed for, the manifest leaves them out
+    pub failed_sessions: HashSet<NaiveDate>,
+}
+
+// the last `periods` completed trading days, most recent first. the
+// calendar window grows with `periods`, so any number of days can be asked for.
+pub async fn trading_days(client: &AlpacaClient, periods: usize) -> Result<Vec<Calendar>, CacheError> {
+    let now = Utc::now();
+    let (start, end) = session::calendar_window(now, periods);
+    let today = now.with_timezone(&New_York).format("%Y-%m-%d").to_string();
+    Ok(completed_sessions(client.get_calendar(start, end).await?, &today, periods)?)
+}
+
+// the last `periods` days of `calendar` (most recent first) before `today`,
+// which is left out since its session hasn't finished yet. an error when
+// there are fewer, rather than quietly working with what there is.
+fn completed_sessions(calendar: Vec<Calendar>, today: &str, periods: usize) -> Result<Vec<Calendar>, SessionError> {
+    let days: Vec<Calendar> = calendar.into_iter()
+        .filter(|day| day.date.as_str() < today)
+        .take(periods)
+        .collect();
+    if days.len() < periods {
+        return Err(SessionError::NotEnoughSessions { needed: periods, found: days.len() });
+    }
+    Ok(days)
+}
+
+async fn cache_symbol(client: &AlpacaClient, cache_dir: &Path, symbol: &str, days: &[Calendar], summary: &Mutex<BuildSummary>) -> io::Result<()> {
+    let path = store::path_for(cache_dir, symbol);
+    // a file from before volume profiles is written again even when no day
+    // is missing
+    let mut outdated = false;
+    let mut history = match BarFile::open(&path) {
+        Ok(file) => {
+            outdated = file.is_outdated();
+            file.history()
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => History::new(),
+        Err(e) => {
+            println!("Rebuilding {}", e);
+            History::new()
+        }
+    };
+    let mut fetched = false;
+    for day in days {
+        let date = match NaiveDate::parse_from_str(&day.date, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => {
+                println!("Bad date {}", day.date);
+                summary.lock().unwrap().failed += 1;
+                continue;
+            }
+        };
+        if history.contains_key(&date) {
+            summary.lock().unwrap().skipped += 1;
+            continue;
+        }
+        let (start, end) = match session::session_bounds(day) {
+            Some((start, end)) => (start.fixed_offset(), end.fixed_offset()),
+            None => {
+                println!("Bad session hours for {}", day.date);
+                let mut summary = summary.lock().unwrap();
+                summary.failed += 1;
+                summary.failed_sessions.insert(date);
+                continue;
+            }
+        };
+        // a failed fetch leaves the day out so the next run tries again,
+        // rather than caching an empty day that looks like no trades
+        let mut bars = match client.get_bars(symbol, Timeframe::OneMin, start, end, Limit::MAX, Adjustment::All).await {
+            Ok(resp) => resp.get_bars().clone(),
+            Err(e) => {
+                println!("Failed {} {}: {}", symbol, day.date, e);
+                let mut summary = summary.lock().unwrap();
+                summary.failed += 1;
+                summary.failed_sessions.insert(date);
+                continue;
+            }
+        };
+        // get_bars returns most recent first, the cache is kept in time order
+        bars.reverse();
+        history.insert(date, bars);
+        fetched = true;
+        summary.lock().unwrap().written += 1;
+    }
+    if fetched || outdated {
+        store::write(&path, &history)?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+// fetch every asset the universe filter allows and cache its 1 minute
+// bars for the last `trading_periods` days. days already cached are left
+// alone, so rerunning an interrupted build picks up where it stopped. a cache
+// still in the old json layout is migrated first.
+pub async fn build(client: &AlpacaClient, options: &BuildOptions) -> Result<BuildSummary, CacheError> {
+    let symbols = universe::select(client, &options.universe).await?;
+    let days = trading_days(client, options.trading_periods).await?;
+    prepare(&options.cache_dir)?;
+    let count = symbols.len();
+    let summary = fetch(client, options, symbols, days.clone()).await?;
+    record(client, options, &covered(days, &summary.failed_sessions), count)?;
+    Ok(summary)
+}
+
+// `days` less any session a fetch failed for. a session only counts as
+// covered once every symbol has it.
+fn covered(days: Vec<Calendar>, failed: &HashSet<NaiveDate>) -> Vec<Calendar> {
+    days.into_iter()
+        .filter(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").is_ok_and(|date| !failed.contains(&date)))
+        .collect()
+}
+
+fn prepare(cache_dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    if store::needs_migration(cache_dir) {
+        let migrated = store::migrate_json(cache_dir)?;
+        println!("Migrated {} symbols ({} days) from json, {} unreadable days will be fetched again",
+                 migrated.symbols, migrated.days, migrated.unreadable);
+    }
+    Ok(())
+}
+
+// cache whatever `days` are missing for each of `symbols`, `options.workers`
+// symbols at a time
+async fn fetch(client: &AlpacaClient, options: &BuildOptions, symbols: Vec<String>, days: Vec<Calendar>) -> Result<BuildSummary, CacheError> {
+    let symbols = Arc::new(symbols);
+    let days = Arc::new(days);
+    let next_index = Arc::new(Mutex::new(0usize));
+    let summary = Arc::new(Mutex::new(BuildSummary {
+        symbols: symbols.len(),
+        ..BuildSummary::default()
+    }));
+    let mut workers: Vec<tokio::task::JoinHandle<io::Result<()>>> = Vec::new();
+    for _ in 0..options.workers.max(1) {
+        let client = client.clone();
+        let symbols = symbols.clone();
+        let days = days.clone();
+        let next_index = next_index.clone();
+        let summary = summary.clone();
+        let cache_dir = options.cache_dir.clone();
+        workers.push(tokio::spawn(async move {
+            loop {
+                let index = {
+                    let mut next_index = next_index.lock().unwrap();
+                    let index = *next_index;
+                    *next_index += 1;
+                    index
+                };
+                if index >= symbols.len() {
+                    return Ok(());
+                }
+                cache_symbol(&client, &cache_dir, &symbols[index], &days, &summary).await?;
+            }
+        }));
+    }
+    for worker in workers {
+        match worker.await {
+            Ok(result) => result?,
+            Err(_) => return Err(io::Error::other("cache worker panicked").into()),
+        }
+    }
+    let summary = summary.lock().unwrap().clone();
+    Ok(summary)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PruneSummary {
+    pub files: usize,
+    // days dropped across all files
+    pub days: usize,
+    // files left with no days at all, which are deleted
+    pub removed: usize,
+}
+
+// drop every cached day before `oldest`, from the manifest too
+pub fn prune(cache_dir: &Path, oldest: NaiveDate) -> io::Result<PruneSummary> {
+    let mut summary = PruneSummary::default();
+    for (_, path) in bar_files(cache_dir)? {
+        // unreadable files are left for verify to report
+        let file = match BarFile::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        summary.files += 1;
+        let stale = file.dates().filter(|date| *date < oldest).count();
+        if stale == 0 {
+            continue;
+        }
+        let history = file.history().split_off(&oldest);
+        drop(file);
+        summary.days += stale;
+        if history.is_empty() {
+            fs::remove_file(&path)?;
+            summary.removed += 1;
+        } else {
+            store::write(&path, &history)?;
+        }
+    }
+    if let Some(mut manifest) = Manifest::load(cache_dir)? {
+        manifest.sessions.retain(|day| *day >= oldest);
+        manifest.write(cache_dir)?;
+    }
+    Ok(summary)
+}
+
+// keep only the last `keep_days` completed trading days. keeping none is
+// refused rather than emptying the cache, and so is a calendar with fewer
+// than `keep_days` sessions, whose oldest day would cut off days the caller
+// wanted kept.
+pub async fn prune_to(client: &AlpacaClient, cache_dir: &Path, keep_days: usize) -> Result<PruneSummary, CacheError> {
+    if keep_days == 0 {
+        return Err(CacheError::Config("--keep-days must be at least 1".to_string()));
+    }
+    let days = trading_days(client, keep_days).await?;
+    let oldest = match days.get(keep_days - 1) {
+        Some(day) => NaiveDate::parse_from_str(&day.date, "%Y-%m-%d")
+            .map_err(|_| CacheError::Config(format!("bad calendar date {}", day.date)))?,
+        None => return Err(SessionError::NotEnoughSessions { needed: keep_days, found: days.len() }.into()),
+    };
+    Ok(prune(cache_dir, oldest)?)
+}
+
+// every symbol alpaca lists as active, whether or not the universe takes it
+fn listed(assets: &[Asset]) -> HashSet<String> {
+    assets.iter()
+        .filter(|asset| asset.status == AssetStatus::Active)
+        .map(|asset| asset.symbol.clone())
+        .collect()
+}
+
+// the symbol and path of every .bars file in the cache, sorted by symbol
+fn bar_files(cache_dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("bars") {
+            continue;
+        }
+        if let Some(symbol) = path.file_stem().and_then(|stem| stem.to_str()) {
+            files.push((symbol.to_string(), path.clone()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // the file can't be read back, the error says why
+    Unreadable(String),
+    // reference days not in the cache, every day when there is no file
+    MissingDays(Vec<NaiveDate>),
+    // days cached with no bars though the symbol traded on them
+    EmptyDays(Vec<NaiveDate>),
+    // the file is for a symbol alpaca no longer lists as active
+    Delisted,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dates = |days: &[NaiveDate]| days.iter().map(|day| day.to_string()).collect::<Vec<_>>().join(", ");
+        match self {
+            Problem::Unreadable(e) => write!(f, "unreadable, {}", e),
+            Problem::MissingDays(days) => write!(f, "missing {}", dates(days)),
+            Problem::EmptyDays(days) => write!(f, "no bars cached for {} but it traded", dates(days)),
+            Problem::Delisted => write!(f, "delisted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub symbol: String,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    // symbols in the universe
+    pub symbols: usize,
+    pub findings: Vec<Finding>,
+    // what fetching the missing days again did, when asked to
+    pub repaired: Option<BuildSummary>,
+}
+
+// check the cache against the universe, the symbols alpaca lists as active
+// and the reference days. every day cached with no bars is reported, verify
+// then keeps the ones the symbol actually traded on.
+pub fn inspect(cache_dir: &Path, universe: &[String], listed: &HashSet<String>, days: &[NaiveDate]) -> io::Result<Vec<Finding>> {
+    let files: HashMap<String, PathBuf> = bar_files(cache_dir)?.into_iter().collect();
+    let mut findings = Vec::new();
+    let finding = |symbol: &str, problem| Finding { symbol: symbol.to_string(), problem };
+    for (symbol, path) in &files {
+        if !listed.contains(symbol) {
+            findings.push(finding(symbol, Problem::Delisted));
+        } else if let Err(e) = BarFile::open(path) {
+            findings.push(finding(symbol, Problem::Unreadable(e.to_string())));
+        }
+    }
+    for symbol in universe {
+        let file = match files.get(symbol).map(|path| BarFile::open(path)) {
+            Some(Ok(file)) => file,
+            Some(Err(_)) => continue,
+            None => {
+                findings.push(finding(symbol, Problem::MissingDays(days.to_vec())));
+                continue;
+            }
+        };
+        let missing: Vec<NaiveDate> = days.iter().copied().filter(|day| file.day(*day).is_none()).collect();
+        let empty: Vec<NaiveDate> = days.iter().copied().filter(|day| file.day(*day).is_some_and(|bars| bars.is_empty())).collect();
+        if !missing.is_empty() {
+            findings.push(finding(symbol, Problem::MissingDays(missing)));
+        }
+        if !empty.is_empty() {
+            findings.push(finding(symbol, Problem::EmptyDays(empty)));
+        }
+    }
+    findings.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    Ok(findings)
+}
+
+// the days each of `symbols` had a daily bar with volume between `start`
+// and `end`
+async fn traded_days(client: &AlpacaClient, symbols: &[String], start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> Result<HashMap<String, HashSet<NaiveDate>>, AlpacaClientError> {
+    let mut traded = HashMap::new();
+    for batch in symbols.chunks(alpaca::MAX_SYMBOLS_PER_REQUEST) {
+        let bars = client.get_multi_bars(batch, Timeframe::OneDay, start, end, Limit::MAX, Adjustment::All).await?;
+        for (symbol, bars) in bars {
+            let days = bars.iter()
+                .filter(|bar| bar.v > 0)
+                .map(|bar| bar.t.with_timezone(&New_York).date_naive())
+                .collect();
+            traded.insert(symbol, days);
+        }
+    }
+    Ok(traded)
+}
+
+// find missing, unreadable, wrongly empty and delisted cache files. with
+// `repair` delisted files are deleted and everything else fetched again.
+pub async fn verify(client: &AlpacaClient, options: &BuildOptions, repair: bool) -> Result<VerifyReport, CacheError> {
+    let assets = client.get_assets().await?;
+    let universe = options.universe.select(&assets);
+    let listed = listed(&assets);
+    let days = trading_days(client, options.trading_periods).await?;
+    let dates: Vec<NaiveDate> = days.iter()
+        .filter_map(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok())
+        .collect();
+    prepare(&options.cache_dir)?;
+    let mut findings = inspect(&options.cache_dir, &universe, &listed, &dates)?;
+
+    // a day with no bars is fine for a symbol that didn't trade, so ask for
+    // daily bars and keep only the days that had volume
+    let empty: Vec<String> = findings.iter()
+        .filter(|finding| matches!(finding.problem, Problem::EmptyDays(_)))
+        .map(|finding| finding.symbol.clone())
+        .collect();
+    // daily bars are stamped 00:00 in New York, before the 04:00 session
+    // open, so the oldest day's bar only comes back from its midnight
+    let first = days.last().and_then(|day| session::new_york_time(&day.date, "00:00"));
+    let last = days.first().and_then(session::session_bounds);
+    if let (false, Some(start), Some((_, end))) = (empty.is_empty(), first, last) {
+        let traded = traded_days(client, &empty, start.fixed_offset(), end.fixed_offset()).await?;
+        for finding in &mut findings {
+            if let Problem::EmptyDays(days) = &mut finding.problem {
+                let traded = traded.get(&finding.symbol);
+                days.retain(|day| traded.is_some_and(|traded| traded.contains(day)));
+            }
+        }
+        findings.retain(|finding| finding.problem != Problem::EmptyDays(Vec::new()));
+    }
+
+    let mut report = VerifyReport {
+        symbols: universe.len(),
+        findings,
+        repaired: None,
+    };
+    if repair {
+        let repaired = repair_findings(client, options, &report.findings, days.clone()).await?;
+        record(client, options, &covered(days, &repaired.failed_sessions), universe.len())?;
+        report.repaired = Some(repaired);
+    }
+    Ok(report)
+}
+
+async fn repair_findings(client: &AlpacaClient, options: &BuildOptions, findings: &[Finding], days: Vec<Calendar>) -> Result<BuildSummary, CacheError> {
+    let mut symbols: Vec<String> = Vec::new();
+    for finding in findings {
+        let path = store::path_for(&options.cache_dir, &finding.symbol);
+        match &finding.problem {
+            Problem::Delisted => {
+                fs::remove_file(&path)?;
+                continue;
+            },
+            // cache_symbol starts over from a file it can't read
+            Problem::Unreadable(_) | Problem::MissingDays(_) => {},
+            // forget the empty days so they count as missing
+            Problem::EmptyDays(empty) => {
+                let mut history = BarFile::open(&path)?.history();
+                history.retain(|day, _| !empty.contains(day));
+                store::write(&path, &history)?;
+            },
+        }
+        symbols.push(finding.symbol.clone());
+    }
+    symbols.dedup();
+    fetch(client, options, symbols, days).await
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UpdateSummary {
+    // sessions the manifest didn't have yet
+    pub sessions: Vec<NaiveDate>,
+    // files the new sessions were added to
+    pub updated: usize,
+    // sessions whose request failed for some batch, the next update tries again
+    pub failed: Vec<NaiveDate>,
+    // symbols in the universe with no cache file yet, e.g. new listings
+    pub listed: usize,
+    // cached symbols missing a session the manifest covers, e.g. after a
+    // failed backfill, fetched again along with the new listings
+    pub incomplete: usize,
+    pub backfill: BuildSummary,
+    // files of delisted symbols that were deleted
+    pub retired: usize,
+}
+
+#[derive(Debug, Default)]
+struct BatchUpdate {
+    written: usize,
+    failed: Vec<NaiveDate>,
+    // files that couldn't be read, they are fetched again from scratch
+    rebuild: Vec<String>,
+}
+
+// add `days` to the files of one batch of symbols with one bars request per
+// day. a symbol missing from a day's response had no trades and gets an
+// empty day.
+async fn update_batch(client: &AlpacaClient, cache_dir: &Path, symbols: &[String], days: &[Calendar]) -> io::Result<BatchUpdate> {
+    let mut update = BatchUpdate::default();
+    let mut fetched: Vec<(NaiveDate, HashMap<String, Vec<Bar>>)> = Vec::new();
+    for day in days {
+        let date = match NaiveDate::parse_from_str(&day.date, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => continue,
+        };
+        let (start, end) = match session::session_bounds(day) {
+            Some((start, end)) => (start.fixed_offset(), end.fixed_offset()),
+            None => {
+                update.failed.push(date);
+                continue;
+            }
+        };
+        match client.get_multi_bars(symbols, Timeframe::OneMin, start, end, Limit::MAX, Adjustment::All).await {
+            Ok(bars) => fetched.push((date, bars)),
+            Err(e) => {
+                println!("Failed {} symbols from {} on {}: {}", symbols.len(), symbols[0], day.date, e);
+                update.failed.push(date);
+            }
+        }
+    }
+    if fetched.is_empty() {
+        return Ok(update);
+    }
+    for symbol in symbols {
+        let path = store::path_for(cache_dir, symbol);
+        let mut history = match BarFile::open(&path) {
+            Ok(file) => file.history(),
+            Err(_) => {
+                update.rebuild.push(symbol.clone());
+                continue;
+            }
+        };
+        for (date, bars) in &mut fetched {
+            // most recent first from alpaca, the cache is kept in time order
+            let mut day_bars = bars.remove(symbol).unwrap_or_default();
+            day_bars.reverse();
+            history.entry(*date).or_insert(day_bars);
+        }
+        store::write(&path, &history)?;
+        update.written += 1;
+    }
+    Ok(update)
+}
+
+// the days of `days` the manifest doesn't cover. all of them when there is
+// no manifest or it is for another feed.
+fn new_sessions(days: &[Calendar], manifest: Option<&Manifest>, feed: Feed) -> Vec<Calendar> {
+    let known = match manifest {
+        Some(manifest) if manifest.feed == feed => manifest.sessions.as_slice(),
+        _ => &[],
+    };
+    days.iter()
+        .filter(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").is_ok_and(|date| !known.contains(&date)))
+        .cloned()
+        .collect()
+}
+
+// whether `symbol`'s file has every one of `dates`. an unreadable file
+// doesn't.
+fn has_days(cache_dir: &Path, symbol: &str, dates: &[NaiveDate]) -> bool {
+    match BarFile::open(&store::path_for(cache_dir, symbol)) {
+        Ok(file) => dates.iter().all(|date| file.day(*date).is_some()),
+        Err(_) => false,
+    }
+}
+
+// delete the files of symbols that aren't `listed` any more. returns the
+// symbols still cached and how many files were deleted.
+fn retire(cache_dir: &Path, listed: &HashSet<String>) -> io::Result<(HashSet<String>, usize)> {
+    let mut cached = HashSet::new();
+    let mut retired = 0;
+    for (symbol, path) in bar_files(cache_dir)? {
+        if listed.contains(&symbol) {
+            cached.insert(symbol);
+        } else {
+            fs::remove_file(&path)?;
+            retired += 1;
+        }
+    }
+    Ok((cached, retired))
+}
+
+// bring the cache up to date without a full rebuild: sessions completed
+// since the manifest was written are fetched for every cached symbol in
+// multi symbol batches, symbols with no file yet get every reference day and
+// files of delisted symbols are deleted. with no manifest, or one from
+// another feed, every reference day counts as new.
+pub async fn update(client: &AlpacaClient, options: &BuildOptions) -> Result<UpdateSummary, CacheError> {
+    let assets = client.get_assets().await?;
+    let universe = options.universe.select(&assets);
+    let listed = listed(&assets);
+    let days = trading_days(client, options.trading_periods).await?;
+    prepare(&options.cache_dir)?;
+    let new_days = new_sessions(&days, Manifest::load(&options.cache_dir)?.as_ref(), client.feed);
+    let (cached, retired) = retire(&options.cache_dir, &listed)?;
+    let mut summary = UpdateSummary {
+        sessions: new_days.iter().filter_map(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok()).collect(),
+        retired,
+        ..UpdateSummary::default()
+    };
+    let symbols = universe.len();
+    // the manifest only says every file should have its sessions. a file
+    // that doesn't, e.g. a new listing whose backfill failed, is backfilled
+    // again instead of skipping those sessions for good
+    let known: Vec<NaiveDate> = days.iter()
+        .filter(|day| !new_days.iter().any(|new_day| new_day.date == day.date))
+        .filter_map(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok())
+        .collect();
+    let (existing, mut backfill): (Vec<String>, Vec<String>) = universe.into_iter()
+        .partition(|symbol| cached.contains(symbol) && has_days(&options.cache_dir, symbol, &known));
+    summary.listed = backfill.iter().filter(|symbol| !cached.contains(*symbol)).count();
+    summary.incomplete = backfill.len() - summary.listed;
+
+    if !new_days.is_empty() {
+        let batches: Vec<io::Result<BatchUpdate>> = stream::iter(existing.chunks(alpaca::MAX_SYMBOLS_PER_REQUEST))
+            .map(|batch| update_batch(client, &options.cache_dir, batch, &new_days))
+            .buffer_unordered(options.workers.max(1))
+            .collect()
+            .await;
+        for batch in batches {
+            let batch = batch?;
+            summary.updated += batch.written;
+            summary.failed.extend(batch.failed);
+            backfill.extend(batch.rebuild);
+        }
+        summary.failed.sort();
+        summary.failed.dedup();
+    }
+    summary.backfill = fetch(client, options, backfill, days.clone()).await?;
+
+    let failed: HashSet<NaiveDate> = summary.failed.iter()
+        .chain(&summary.backfill.failed_sessions)
+        .copied()
+        .collect();
+    record(client, options, &covered(days, &failed), symbols)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{completed_sessions, covered, has_days, inspect, new_sessions, prune, prune_to, retire, CacheError, Finding, Manifest, Problem, Staleness};
+    use crate::alpaca::{Calendar, Feed};
+    use crate::session::SessionError;
+    use crate::store::{self, BarFile, History};
+    use crate::test_support::{bar, client, date, day, temp_dir};
+    use chrono::{DateTime, Utc};
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::Path;
+
+    // a file with one bar on each of `traded` and nothing on each of `quiet`
+    fn cache(dir: &Path, symbol: &str, traded: &[&str], quiet: &[&str]) {
+        let mut history = History::new();
+        for day in traded {
+            history.insert(date(day), vec![bar(&format!("{}T14:30:00Z", day), 100)]);
+        }
+        for day in quiet {
+            history.insert(date(day), Vec::new());
+        }
+        store::write(&store::path_for(dir, symbol), &history).unwrap();
+    }
+
+    fn symbols(symbols: &[&str]) -> Vec<String> {
+        symbols.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn prunes_days_before_the_oldest_kept() {
+        let dir = temp_dir("prune");
+        cache(&dir, "AAPL", &["2023-11-20", "2023-11-21", "2023-11-22"], &[]);
+        cache(&dir, "OLD", &["2023-11-20"], &[]);
+        cache(&dir, "NEW", &["2023-11-22"], &[]);
+        fs::write(store::path_for(&dir, "BAD"), b"junk").unwrap();
+
+        let summary = prune(&dir, date("2023-11-21")).unwrap();
+        assert_eq!((summary.files, summary.days, summary.removed), (3, 2, 1));
+        let aapl = BarFile::open(&store::path_for(&dir, "AAPL")).unwrap();
+        assert_eq!(aapl.dates().collect::<Vec<_>>(), vec![date("2023-11-21"), date("2023-11-22")]);
+        assert!(!store::path_for(&dir, "OLD").exists());
+        assert!(store::path_for(&dir, "NEW").exists());
+        assert!(store::path_for(&dir, "BAD").exists(), "unreadable files are left for verify");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inspect_finds_every_kind_of_problem() {
+        let dir = temp_dir("inspect");
+        let days = [date("2023-11-21"), date("2023-11-22")];
+        cache(&dir, "AAPL", &["2023-11-21", "2023-11-22"], &[]);
+        cache(&dir, "MSFT", &["2023-11-22"], &[]);
+        cache(&dir, "QUIET", &["2023-11-21"], &["2023-11-22"]);
+        cache(&dir, "GONE", &["2023-11-21", "2023-11-22"], &[]);
+        cache(&dir, "FILTERED", &["2023-11-21", "2023-11-22"], &[]);
+        fs::write(store::path_for(&dir, "BAD"), b"junk").unwrap();
+        let universe = symbols(&["AAPL", "BAD", "MSFT", "NEW", "QUIET"]);
+        let listed: HashSet<String> = symbols(&["AAPL", "BAD", "FILTERED", "MSFT", "NEW", "QUIET"]).into_iter().collect();
+
+        let findings = inspect(&dir, &universe, &listed, &days).unwrap();
+        let finding = |symbol: &str, problem| Finding { symbol: symbol.to_string(), problem };
+        assert_eq!(findings.len(), 5, "{:?}", findings);
+        assert!(matches!(&findings[0], Finding { symbol, problem: Problem::Unreadable(_) } if symbol == "BAD"));
+        assert_eq!(findings[1..], [
+            finding("GONE", Problem::Delisted),
+            finding("MSFT", Problem::MissingDays(vec![date("2023-11-21")])),
+            finding("NEW", Problem::MissingDays(days.to_vec())),
+            finding("QUIET", Problem::EmptyDays(vec![date("2023-11-22")])),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn manifest(sessions: &[&str]) -> Manifest {
+        Manifest {
+            built_at: DateTime::parse_from_rfc3339("2023-11-23T06:00:00Z").unwrap().with_timezone(&Utc),
+            sessions: sessions.iter().map(|day| date(day)).collect(),
+            feed: Feed::Sip,
+            symbols: 8000,
+        }
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let dir = temp_dir("manifest");
+        assert_eq!(Manifest::load(&dir).unwrap(), None);
+        manifest(&["2023-11-22", "2023-11-21"]).write(&dir).unwrap();
+        assert_eq!(Manifest::load(&dir).unwrap(), Some(manifest(&["2023-11-22", "2023-11-21"])));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn staleness_against_the_needed_sessions() {
+        let cached = manifest(&["2023-11-22", "2023-11-21", "2023-11-20"]);
+        let needed = [date("2023-11-21"), date("2023-11-20")];
+        assert_eq!(Manifest::staleness(Some(&cached), Feed::Sip, &needed), None);
+        assert_eq!(Manifest::staleness(None, Feed::Sip, &needed), Some(Staleness::NoManifest));
+        assert_eq!(Manifest::staleness(Some(&cached), Feed::Iex, &needed),
+                   Some(Staleness::WrongFeed { cached: Feed::Sip, wanted: Feed::Iex }));
+        // the morning after thanksgiving friday, friday isn't cached yet
+        let needed = [date("2023-11-24"), date("2023-11-22"), date("2023-11-21")];
+        let stale = Manifest::staleness(Some(&cached), Feed::Sip, &needed).unwrap();
+        assert_eq!(stale, Staleness::MissingSessions(vec![date("2023-11-24")]));
+        assert_eq!(stale.to_string(), "cache is missing 2023-11-24");
+    }
+
+    #[tokio::test]
+    async fn refuses_to_prune_every_day() {
+        let dir = temp_dir("prune-none");
+        cache(&dir, "AAPL", &["2023-11-22"], &[]);
+        assert!(matches!(prune_to(&client(), &dir, 0).await, Err(CacheError::Config(_))));
+        assert!(BarFile::open(&store::path_for(&dir, "AAPL")).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pruning_updates_the_manifest() {
+        let dir = temp_dir("prune-manifest");
+        cache(&dir, "AAPL", &["2023-11-20", "2023-11-21"], &[]);
+        manifest(&["2023-11-21", "2023-11-20"]).write(&dir).unwrap();
+        prune(&dir, date("2023-11-21")).unwrap();
+        assert_eq!(Manifest::load(&dir).unwrap().unwrap().sessions, vec![date("2023-11-21")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_sessions_the_manifest_lacks_are_new() {
+        let days = [day("2023-11-24"), day("2023-11-22"), day("2023-11-21")];
+        let dates = |days: Vec<Calendar>| days.into_iter().map(|day| day.date).collect::<Vec<_>>();
+        let cached = manifest(&["2023-11-22", "2023-11-21", "2023-11-20"]);
+        assert_eq!(dates(new_sessions(&days, Some(&cached), Feed::Sip)), vec!["2023-11-24"]);
+        assert_eq!(dates(new_sessions(&days, Some(&cached), Feed::Iex)).len(), 3, "another feed's cache covers nothing");
+        assert_eq!(dates(new_sessions(&days, None, Feed::Sip)).len(), 3);
+    }
+
+    #[test]
+    fn takes_completed_sessions_or_refuses() {
+        let calendar = vec![day("2023-11-27"), day("2023-11-24"), day("2023-11-22"), day("2023-11-21")];
+        let dates = |days: Vec<Calendar>| days.into_iter().map(|day| day.date).collect::<Vec<_>>();
+        assert_eq!(dates(completed_sessions(calendar.clone(), "2023-11-27", 2).unwrap()), vec!["2023-11-24", "2023-11-22"]);
+        assert_eq!(completed_sessions(calendar, "2023-11-27", 4).unwrap_err(),
+                   SessionError::NotEnoughSessions { needed: 4, found: 3 });
+    }
+
+    #[test]
+    fn failed_sessions_are_not_covered() {
+        let days = vec![day("2023-11-24"), day("2023-11-22"), day("2023-11-21")];
+        let failed: HashSet<_> = [date("2023-11-22")].into_iter().collect();
+        let dates: Vec<String> = covered(days, &failed).into_iter().map(|day| day.date).collect();
+        assert_eq!(dates, vec!["2023-11-24", "2023-11-21"]);
+    }
+
+    #[test]
+    fn files_missing_a_session_are_incomplete() {
+        let dir = temp_dir("incomplete");
+        cache(&dir, "AAPL", &["2023-11-21"], &["2023-11-22"]);
+        cache(&dir, "NEW", &["2023-11-22"], &[]);
+        fs::write(store::path_for(&dir, "BAD"), b"junk").unwrap();
+        let known = [date("2023-11-21"), date("2023-11-22")];
+        assert!(has_days(&dir, "AAPL", &known), "a day with no trades is still there");
+        assert!(!has_days(&dir, "NEW", &known));
+        assert!(!has_days(&dir, "BAD", &known));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retires_delisted_symbols() {
+        let dir = temp_dir("retire");
+        cache(&dir, "AAPL", &["2023-11-22"], &[]);
+        cache(&dir, "GONE", &["2023-11-22"], &[]);
+        let listed: HashSet<String> = symbols(&["AAPL", "NEW"]).into_iter().collect();
+        let (cached, retired) = retire(&dir, &listed).unwrap();
+        assert_eq!(cached, symbols(&["AAPL"]).into_iter().collect());
+        assert_eq!(retired, 1);
+        assert!(!store::path_for(&dir, "GONE").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
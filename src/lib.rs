@@ -1,2 +1,9 @@
 pub mod alpaca;
-
+pub mod cache;
+pub mod stream;
+pub mod universe;
+pub mod session;
+pub mod volume;
+pub mod store;
+#[cfg(test)]
+mod test_support;
@@ -1,6 +1,5 @@
 use std::io;
-use std::fs::{self, DirEntry, ReadDir};
-use std::path::{Path};
+use std::path::Path;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
@@ -10,8 +9,8 @@ use std::{
     error::Error,
     time::{Duration, Instant},
 };
-use std::thread;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout},
@@ -20,49 +19,32 @@ use tui::{
     widgets::{Block, Borders, List, ListItem, ListState},
     Frame, Terminal,
 };
-use chrono::Timelike;
-use chrono::{DateTime, Local, NaiveTime, TimeZone, FixedOffset};
+use chrono::{DateTime, NaiveDate, FixedOffset};
 use chrono_tz::America::New_York;
-use chrono::{Utc, Offset};
-use rvat_scanner::alpaca::Bar;
-use rvat_scanner::alpaca;
-use std::collections::HashSet;
+use rvat_scanner::alpaca::{self, Adjustment, AlpacaClient, AlpacaClientError, Bar, Calendar, Feed, Limit, RetryPolicy, Snapshot, Timeframe};
+use rvat_scanner::cache;
+use rvat_scanner::session::{self, SessionPhase};
+use rvat_scanner::universe::{self, UniverseFilter};
+use rvat_scanner::store::{self, BarFile};
+use rvat_scanner::volume::{self, ShortSessions};
+use rvat_scanner::stream::{self, BarStream, CumulativeVolume, StreamEvent, StreamOptions};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use std::collections::{HashMap, HashSet};
+use std::env;
 
 static LIST_ITEM_HEIGHT:u16 = 100;
 static LIST_PAGE_SIZE:usize = 50;
-static THREADS:usize = 5;
-
-use serde::Deserialize;
-#[derive(Deserialize, Debug, Clone)]
-pub struct Ticker {
-    ticker:String
-}
-
-use lazy_static::lazy_static;
-lazy_static! {
-    pub static ref SYMBOLS:Vec<String> = read_cache_folders(Path::new("cache")).unwrap();
-    pub static ref EXCLUDED_SYMBOLS:Vec<Ticker> = serde_json::from_str(
-        fs::read_to_string("excluded_tickers.json").unwrap().as_str()
-    ).unwrap();
-}
-
-fn read_cache_folders(folder_path:&Path) -> io::Result<Vec<String>> {
-    let mut symbols:Vec<String> = Vec::new();
-    // read the folders in the '../cache' directory
-    let entries:ReadDir = fs::read_dir(folder_path)?;
-    for entry in entries {
-        let entry:DirEntry = entry?;
-        // each folder name is a symbol
-        let folder_name:String = entry.file_name().into_string().unwrap();
-        // ignore .DS_Store files
-        if folder_name == ".DS_Store" {
-            continue;
-        }
-        symbols.push(folder_name);
-    }
-    assert!(symbols.len() > 0, "no symbols found in cache, run node build_cache.js first");
-    Ok(symbols)
-}
+// batches being scanned at once
+static CONCURRENCY:usize = 200;
+// symbols per multi symbol bars request
+static BATCH_SIZE:usize = alpaca::MAX_SYMBOLS_PER_REQUEST;
+static CACHE_DIR:&str = "cache";
+// sessions each symbol's volume is compared against
+static REFERENCE_SESSIONS:usize = 17;
+// how often the clock is checked while the market is closed
+static CLOSED_POLL_INTERVAL:Duration = Duration::from_secs(60);
 
 struct StatefulList<T> {
     state: ListState,
@@ -113,12 +95,29 @@ struct Analysis {
     analysis_dvat:u64,
     score:f64,
     pnl_change_percent:f64,
-    created_at:DateTime<FixedOffset>
+    created_at:DateTime<FixedOffset>,
+    // from the symbol's snapshot, when there is one
+    last_price:Option<f64>,
+    spread:Option<f64>,
+    previous_close:Option<f64>,
+    daily_bar:Option<Bar>,
+}
+
+impl Analysis {
+    fn with_snapshot(mut self, snapshot:&Snapshot, session:NaiveDate) -> Analysis {
+        self.last_price = snapshot.last_price();
+        self.spread = snapshot.spread();
+        self.previous_close = snapshot.previous_close(session);
+        self.daily_bar = snapshot.session_bar(session).cloned();
+        self
+    }
 }
 
 struct App { 
     items: StatefulList<Analysis>,
-    title: String
+    title: String,
+    // shown after the title for the whole run, e.g. a stale cache
+    warning: Option<String>
 }
 
 
@@ -126,7 +125,8 @@ impl App {
     fn new() -> App {
         App {
             items: StatefulList::with_items(vec![ ]),
-            title: String::from("RVAT Scanner")
+            title: String::from("RVAT Scanner"),
+            warning: None
         }
     }
 
@@ -170,16 +170,179 @@ impl App {
         }
     }
 
+    // drop every row, e.g. when a new session starts
+    fn clear(&mut self) {
+        self.items = StatefulList::with_items(vec![ ]);
+    }
+
     fn set_title(&mut self, title:&str) {
         self.title = String::from(title);
     }
 
+    fn title(&self) -> String {
+        match &self.warning {
+            Some(warning) => format!("{} ({})", self.title, warning),
+            None => self.title.clone(),
+        }
+    }
+
     fn on_tick(&mut self) {
 
     }
 }
 
+// value following `flag` in args, e.g. `--days 21`. a value that doesn't
+// parse reports why.
+fn flag_value<T: std::str::FromStr>(args: &[String], flag: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T::Err: std::fmt::Display,
+{
+    match args.iter().position(|a| a == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => match value.parse::<T>() {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(format!("{}: {}", flag, e).into()),
+            },
+            None => Err(format!("{} expects a value", flag).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+// which assets to scan and cache, from `--exchanges NYSE,NASDAQ`,
+// `--shortable`, `--easy-to-borrow`, `--fractionable` and excluded_tickers.json
+fn universe_filter(args: &[String]) -> Result<UniverseFilter, Box<dyn Error>> {
+    let mut filter = UniverseFilter::default();
+    if let Some(exchanges) = flag_value::<String>(args, "--exchanges")? {
+        filter.exchanges = exchanges.split(',')
+            .map(|e| e.trim().to_uppercase())
+            .filter(|e| !e.is_empty())
+            .collect();
+    }
+    filter.require_shortable = args.iter().any(|a| a == "--shortable");
+    filter.require_easy_to_borrow = args.iter().any(|a| a == "--easy-to-borrow");
+    filter.require_fractionable = args.iter().any(|a| a == "--fractionable");
+    filter.excluded = universe::load_excluded(Path::new("excluded_tickers.json"))?;
+    Ok(filter)
+}
+
+// move a cache from the old cache/<SYMBOL>/<date>.json layout to .bars files
+fn migrate_cache() -> Result<(), Box<dyn Error>> {
+    let summary = store::migrate_json(Path::new(CACHE_DIR))?;
+    println!("Migrated {} symbols ({} days), {} unreadable days left for the next build to fetch",
+             summary.symbols, summary.days, summary.unreadable);
+    Ok(())
+}
+
+// `--days N` and `--workers N` for the cache commands that fetch
+fn build_options(filter: UniverseFilter, args: &[String]) -> Result<cache::BuildOptions, Box<dyn Error>> {
+    let mut options = cache::BuildOptions {
+        universe: filter,
+        ..cache::BuildOptions::default()
+    };
+    if let Some(days) = flag_value(args, "--days")? {
+        options.trading_periods = days;
+    }
+    if let Some(workers) = flag_value(args, "--workers")? {
+        options.workers = workers;
+    }
+    Ok(options)
+}
+
+// compare the cache manifest with the reference sessions the scanner is
+// about to use. a stale cache is updated when `refresh` is set, otherwise the
+// returned warning is shown with the title.
+fn check_cache(runtime: &Runtime, client: &AlpacaClient, filter: &UniverseFilter, refresh: bool) -> Result<Option<String>, Box<dyn Error>> {
+    let now = chrono::Utc::now();
+    let (start, end) = session::calendar_window(now, REFERENCE_SESSIONS);
+    let trading_days = runtime.block_on(client.get_calendar(start, end))?;
+    let sessions = session::resolve_sessions(now, &trading_days, REFERENCE_SESSIONS)?;
+    let needed:Vec<NaiveDate> = sessions.reference.iter()
+        .filter_map(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok())
+        .collect();
+    let manifest = cache::Manifest::load(Path::new(CACHE_DIR))?;
+    let staleness = match cache::Manifest::staleness(manifest.as_ref(), client.feed, &needed) {
+        Some(staleness) => staleness,
+        None => return Ok(None),
+    };
+    if !refresh {
+        return Ok(Some(format!("{}, run cache build or start with --refresh", staleness)));
+    }
+    println!("{}, refreshing", staleness);
+    let options = cache::BuildOptions {
+        universe: filter.clone(),
+        trading_periods: cache::TRADING_PERIODS.max(REFERENCE_SESSIONS),
+        ..cache::BuildOptions::default()
+    };
+    print_update(&runtime.block_on(cache::update(client, &options))?);
+    Ok(None)
+}
+
+fn print_update(summary: &cache::UpdateSummary) {
+    println!("Added {} new sessions to {} files, {} sessions failed and are fetched again next time",
+             summary.sessions.len(), summary.updated, summary.failed.len());
+    println!("Backfilled {} new and {} incomplete symbols ({} days written, {} failed), retired {} delisted",
+             summary.listed, summary.incomplete, summary.backfill.written, summary.backfill.failed, summary.retired);
+}
+
+fn run_cache_command(runtime: &Runtime, client: &AlpacaClient, filter: UniverseFilter, args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.first().map(String::as_str) {
+        Some("build") => {
+            let options = build_options(filter, args)?;
+            let summary = runtime.block_on(cache::build(client, &options))?;
+            println!("Cached {} symbols: {} days written, {} already cached, {} failed",
+                     summary.symbols, summary.written, summary.skipped, summary.failed);
+            Ok(())
+        },
+        Some("update") => {
+            let options = build_options(filter, args)?;
+            print_update(&runtime.block_on(cache::update(client, &options))?);
+            Ok(())
+        },
+        Some("migrate") => migrate_cache(),
+        Some("prune") => {
+            let keep_days = flag_value::<usize>(args, "--keep-days")?.ok_or("cache prune expects --keep-days N")?;
+            let summary = runtime.block_on(cache::prune_to(client, Path::new(CACHE_DIR), keep_days))?;
+            println!("Pruned {} days from {} files, {} files left empty were removed",
+                     summary.days, summary.files, summary.removed);
+            Ok(())
+        },
+        Some("verify") => {
+            let options = build_options(filter, args)?;
+            let report = runtime.block_on(cache::verify(client, &options, args.iter().any(|a| a == "--repair")))?;
+            for finding in &report.findings {
+                println!("{}: {}", finding.symbol, finding.problem);
+            }
+            println!("Checked {} symbols, {} problems", report.symbols, report.findings.len());
+            if let Some(summary) = report.repaired {
+                println!("Repaired: {} days written, {} failed", summary.written, summary.failed);
+            }
+            Ok(())
+        },
+        _ => Err("usage: rvat-scanner cache build [--days N] [--workers N] | cache update [--days N] [--workers N] | cache migrate | cache prune --keep-days N | cache verify [--days N] [--repair], each with [--feed sip|iex|otc] [--exchanges A,B] [--shortable] [--easy-to-borrow] [--fractionable]".into()),
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    let args:Vec<String> = env::args().skip(1).collect();
+    // one runtime and one client for the whole process, so every request
+    // shares the connection pool and the rate limiter
+    let runtime = Runtime::new()?;
+    let client = match flag_value::<Feed>(&args, "--feed")? {
+        Some(feed) => AlpacaClient::with_feed(feed)?,
+        None => AlpacaClient::new()?,
+    };
+    let filter = universe_filter(&args)?;
+    // a subcommand only counts as the first argument, so a flag value that
+    // happens to be "cache" doesn't start one
+    if args.first().map(String::as_str) == Some("cache") {
+        return run_cache_command(&runtime, &client, filter, &args[1..]);
+    }
+    if store::needs_migration(Path::new(CACHE_DIR)) {
+        migrate_cache()?;
+    }
+    let warning = check_cache(&runtime, &client, &filter, args.iter().any(|a| a == "--refresh"))?;
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -190,8 +353,15 @@ fn main() -> Result<(), Box<dyn Error>> {
     // create app and run it
     let tick_rate = Duration::from_millis(250);
     //let app = App::new();
-    let app = Arc::new(Mutex::new(App::new()));
-    let res = run_app(&mut terminal, app.clone(), tick_rate);
+    let mut app = App::new();
+    app.warning = warning;
+    let app = Arc::new(Mutex::new(app));
+    let options = ScanOptions {
+        universe: filter,
+        short_sessions: flag_value::<ShortSessions>(&args, "--short-sessions")?.unwrap_or_default(),
+        streaming: args.iter().any(|a| a == "--stream"),
+    };
+    let res = run_app(&mut terminal, app.clone(), tick_rate, runtime.handle().clone(), client, options);
 
     // restore terminal
     disable_raw_mode()?;
@@ -209,199 +379,490 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-// hour_minute is like "04:00"
-// reurn DateTime<FixedOffset> in New York
-//fn time_in_new_york (hour_minute:&str) -> DateTime<FixedOffset> {
-    //let naive_time = NaiveTime::parse_from_str(hour_minute, "%H:%M").unwrap();
-    ////let local_date = Local::today().naive_local();
-    //let local_date = Local::now().naive_local().date();
-    //let local_datetime = local_date.and_time(naive_time);
-    //// TODO: need a more robust solution for handling DST.
-    ////let ny_offset = FixedOffset::west(5 * 3600); // UTC-5 hours for Eastern Standard Time
-    //let ny_offset = FixedOffset::west_opt(5 * 3600).unwrap(); // UTC-5 hours for Eastern Daylight Time
-    //let ny_datetime = ny_offset.from_local_datetime(&local_datetime).unwrap();
-    //ny_datetime
-//}
+// how the scanner was asked to run
+struct ScanOptions {
+    universe:UniverseFilter,
+    short_sessions:ShortSessions,
+    // follow the websocket instead of polling
+    streaming:bool,
+}
 
-fn time_in_new_york(hour_minute: &str) -> DateTime<FixedOffset> {
-    let naive_time = NaiveTime::parse_from_str(hour_minute, "%H:%M").unwrap();
-    let local_date = Local::now().naive_local().date();
-    let local_datetime = local_date.and_time(naive_time);
+// what every scan task needs to know about the pass
+struct ScanContext {
+    analysis_day:Calendar,
+    // analysis_day's date and extended hours
+    session:NaiveDate,
+    session_open:DateTime<FixedOffset>,
+    session_close:DateTime<FixedOffset>,
+    reference_days:Vec<Calendar>,
+    // each symbol's cache file, mapped once for the whole run
+    reference_files:HashMap<String, BarFile>,
+    // the universe to scan
+    symbols:Vec<String>,
+    // recent trading days, most recent first, for working out the session phase
+    calendar:Vec<Calendar>,
+    // how reference days with different hours, e.g. half days, are compared
+    short_sessions:ShortSessions,
+    // what the universe was selected with, to select it again for a new session
+    universe:UniverseFilter,
+    // the reference cache has to come from the same feed
+    feed:Feed,
+}
 
-    // Find the equivalent UTC datetime
-    let utc_datetime = Utc.from_local_datetime(&local_datetime).unwrap();
-    let naive_utc_datetime = utc_datetime.naive_utc();
-    // Convert the UTC datetime to New York time, considering DST
-    let ny_datetime_with_tz = New_York.from_utc_datetime(&naive_utc_datetime);
+// average volume traded in the first `minute` minutes of the analysis session
+// across the cached reference days, each read up to the comparable minute of
+// its own session. None when nothing is cached for the symbol.
+fn average_reference_volume(symbol:&str, context:&ScanContext, minute:i64) -> Option<f64> {
+    let file = context.reference_files.get(symbol)?;
+    let mut volumes:Vec<u64> = Vec::new();
+    for reference_day in &context.reference_days {
+        let reference_minute = match volume::reference_minute(&context.analysis_day, reference_day,
+                                                              minute, context.short_sessions) {
+            Some(reference_minute) => reference_minute,
+            None => continue,
+        };
+        let bars = match NaiveDate::parse_from_str(&reference_day.date, "%Y-%m-%d").ok()
+            .and_then(|date| file.day(date)) {
+            Some(bars) => bars,
+            None => continue,
+        };
+        if let Some(volume) = bars.volume_through_minute(reference_day, reference_minute) {
+            volumes.push(volume);
+        }
+    }
+    if volumes.is_empty() {
+        return None;
+    }
+    Some(volumes.iter().sum::<u64>() as f64 / volumes.len() as f64)
+}
 
-    // Extract the fixed offset
-    let fixed_offset = ny_datetime_with_tz.offset().fix();
+// how far into the analysis session we are. a past session is over, so all
+// of each reference day counts.
+fn session_minute(context:&ScanContext) -> i64 {
+    volume::minutes_into_session(&context.analysis_day, chrono::Utc::now()).unwrap_or(i64::MAX)
+}
 
-    // Apply the fixed offset to the original naive datetime
-    fixed_offset.from_local_datetime(&local_datetime).unwrap()
+// resolve the session to analyze and its reference sessions as of now,
+// select the universe and map each symbol's cache file
+async fn scan_context(client:&AlpacaClient, universe:UniverseFilter, short_sessions:ShortSessions) -> io::Result<ScanContext> {
+    let now = chrono::Utc::now();
+    let (start, end) = session::calendar_window(now, REFERENCE_SESSIONS);
+    let trading_days = client.get_calendar(start, end).await.map_err(io::Error::other)?;
+    let sessions = session::resolve_sessions(now, &trading_days, REFERENCE_SESSIONS).map_err(io::Error::other)?;
+    let session = NaiveDate::parse_from_str(&sessions.current.date, "%Y-%m-%d").map_err(io::Error::other)?;
+    let (session_open, session_close) = session::session_bounds(&sessions.current)
+        .ok_or_else(|| io::Error::other(format!("bad session hours for {}", sessions.current.date)))?;
+    let symbols = universe::select(client, &universe).await.map_err(io::Error::other)?;
+    // symbols with no readable cache file just go unscored. mapping
+    // thousands of files is blocking work, keep it off the async workers
+    let reference_symbols = symbols.clone();
+    let reference_files = tokio::task::spawn_blocking(move || {
+        reference_symbols.iter()
+            .filter_map(|symbol| Some((symbol.clone(), BarFile::open(&store::path_for(Path::new(CACHE_DIR), symbol)).ok()?)))
+            .collect()
+    }).await.map_err(io::Error::other)?;
+    Ok(ScanContext {
+        analysis_day:sessions.current,
+        session,
+        session_open:session_open.fixed_offset(),
+        session_close:session_close.fixed_offset(),
+        reference_days:sessions.reference,
+        reference_files,
+        symbols,
+        calendar:trading_days,
+        short_sessions,
+        universe,
+        feed:client.feed,
+    })
 }
 
+// a fresh context once the one in use is for a session that's over, e.g.
+// after idling overnight. None while it's still current, or when the new
+// one can't be loaded yet, which the title then says.
+async fn next_context(app:&Arc<Mutex<App>>, client:&AlpacaClient, context:&ScanContext) -> Option<ScanContext> {
+    if !session::sessions_outdated(&context.analysis_day, chrono::Utc::now(), &context.calendar) {
+        return None;
+    }
+    match scan_context(client, context.universe.clone(), context.short_sessions).await {
+        Ok(context) => {
+            app.lock().unwrap().clear();
+            Some(context)
+        },
+        Err(e) => {
+            app.lock().unwrap().set_title(format!("RVAT Scanner {} {} new session failed to load ({})",
+                                                  context.feed, context.analysis_day.date, e).as_str());
+            None
+        }
+    }
+}
 
-fn run_app<B: Backend>(
-    terminal: &mut Terminal<B>,
-    /*mut */app: Arc<Mutex<App>>,
-    tick_rate: Duration,
-) -> io::Result<()> {
-    let symbol_index:usize = 0;
-    let symbol_index_ptr = Arc::new(Mutex::new(symbol_index));
-    let loops:usize = 0;
-    let loops_ptr = Arc::new(Mutex::new(loops));
-    let excluded_symbols:HashSet<&str> = HashSet::<&str>::from_iter(EXCLUDED_SYMBOLS.iter().map(|t| t.ticker.as_str()));
-    let excluded_symbols_ptr = Arc::new(Mutex::new(excluded_symbols));
-    fn next_symbol(symbol_index_ptr:Arc<Mutex<usize>>, loops_ptr: Arc<Mutex<usize>>) -> (usize, String) {
-        let mut symbol_index = symbol_index_ptr.lock().unwrap();
-        *symbol_index += 1;
-        if *symbol_index >= SYMBOLS.len() {
-            *symbol_index = 0;
-            let mut loops = loops_ptr.lock().unwrap();
-            *loops += 1;
+// score one symbol from today's bars, most recent first. None when there
+// is nothing to show, e.g. no trades today.
+fn analyze(symbol:String, average_dvat:f64, bars:&[Bar]) -> Option<Analysis> {
+    if bars.is_empty() {
+        return None;
+    }
+    let analysis_dvat:u64 = bars.iter().map(|bar| bar.v).sum();
+    score(symbol, average_dvat, analysis_dvat, bars[bars.len() - 1].c, bars[0].c)
+}
+
+// score from today's volume so far and the first and latest close
+fn score(symbol:String, average_dvat:f64, analysis_dvat:u64, earliest_close:f64, latest_close:f64) -> Option<Analysis> {
+    if analysis_dvat == 0 {
+        return None;
+    }
+    // find the % change from the first bar to the latest bar
+    let pnl_change_percent:f64 = (latest_close - earliest_close) / latest_close;
+    Some(Analysis {
+        symbol,
+        average_dvat:average_dvat as u64,
+        analysis_dvat,
+        score:analysis_dvat as f64 / average_dvat,
+        pnl_change_percent,
+        created_at:chrono::Utc::now().into(),
+        last_price:None,
+        spread:None,
+        previous_close:None,
+        daily_bar:None,
+    })
+}
+
+// score a batch of symbols with one multi symbol bars request. an Err means
+// the request failed for the whole batch.
+async fn scan_batch(client:&AlpacaClient, symbols:Vec<String>, context:Arc<ScanContext>) -> Result<Vec<Analysis>, AlpacaClientError> {
+    // a first read of the mapped cache can fault pages in from disk, keep it
+    // off the async workers
+    let reference_context = context.clone();
+    let minute = session_minute(&context);
+    let averages:Vec<(String, f64)> = tokio::task::spawn_blocking(move || {
+        /*
+         * where do you cut off average_dvat?
+         * this value is the average of the last 17 days
+         * if it's absurdly low and the stock is highly illiquid,
+         * we get a false positive high score.
+         * a score of 35513855 / 16164 = 2195.5 is absurdly high and 
+         * what we are looking for.
+         *
+         * 61000 / 20 = 3005 is a better score but it's because the 
+         * divisor is so low
+         *
+         * let's start with 350
+         * now trying 1000
+         */
+        symbols.into_iter()
+            .filter_map(|symbol| {
+                let average_dvat = average_reference_volume(&symbol, &reference_context, minute)?;
+                (average_dvat >= 1000.0).then_some((symbol, average_dvat))
+            })
+            .collect()
+    }).await.unwrap_or_default();
+    if averages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let symbols:Vec<String> = averages.iter().map(|(symbol, _)| symbol.clone()).collect();
+    let mut analysis_day_bars = client.get_multi_bars(&symbols,
+                                                      Timeframe::OneMin,
+                                                      context.session_open,
+                                                      context.session_close,
+                                                      Limit::MAX,
+                                                      Adjustment::All).await?;
+    // the scores stand on their own, so a failed snapshot request only
+    // leaves the price columns empty
+    let snapshots = client.get_snapshots(&symbols).await.unwrap_or_default();
+    Ok(averages.into_iter()
+        .filter_map(|(symbol, average_dvat)| {
+            let bars = analysis_day_bars.remove(&symbol)?;
+            let analysis = analyze(symbol, average_dvat, &bars)?;
+            Some(match snapshots.get(&analysis.symbol) {
+                Some(snapshot) => analysis.with_snapshot(snapshot, context.session),
+                None => analysis,
+            })
+        })
+        .collect())
+}
+
+// scan every symbol, pass after pass, in batches of BATCH_SIZE with at most
+// CONCURRENCY batches in flight. the rate limiter in the client decides how
+// fast they actually go.
+async fn scan_market(app:Arc<Mutex<App>>, client:AlpacaClient, context:ScanContext) {
+    let mut context = Arc::new(context);
+    let semaphore = Arc::new(Semaphore::new(CONCURRENCY));
+    // symbols whose request failed, as opposed to symbols that simply had no trades
+    let failures = Arc::new(AtomicUsize::new(0));
+    let mut loops:usize = 0;
+    loop {
+        // a run left going overnight moves on to the new session
+        if let Some(next) = next_context(&app, &client, &context).await {
+            context = Arc::new(next);
+            failures.store(0, Ordering::Relaxed);
+            loops = 0;
         }
-        (*symbol_index, SYMBOLS[*symbol_index].clone())
-    }
-    for _ in 0..THREADS {
-        let app_clone = app.clone();
-        let symbol_index_ptr = symbol_index_ptr.clone();
-        let loops_ptr = loops_ptr.clone();
-        let excluded_symbols_ptr = excluded_symbols_ptr.clone();
-        thread::spawn(move || {
-            let now = chrono::DateTime::from(chrono::Utc::now());
-            let start = now - chrono::Duration::days(60);
-            let trading_days = alpaca::get_calendar(
-                start, now);
-            let analysis_day = trading_days[0].clone();
-            let reference_days = trading_days[1..18].to_vec();
-            loop {
-                let (symbol_index, symbol) = next_symbol(symbol_index_ptr.clone(), loops_ptr.clone());
-                if excluded_symbols_ptr.lock().unwrap().contains(symbol.as_str()) {
+        let symbols:Vec<String> = context.symbols.clone();
+        // nothing changes while the market is closed, so after one pass to
+        // show the last session just keep an eye on the clock
+        let phase = match client.get_clock().await {
+            Ok(clock) => {
+                let phase = session::session_phase(&clock, &context.calendar);
+                if phase == SessionPhase::Closed && loops > 0 {
+                    let next_open = clock.next_open.with_timezone(&New_York).format("%a %H:%M");
+                    app.lock().unwrap().set_title(format!("RVAT Scanner {} {} closed, opens {}",
+                                                          context.feed, context.analysis_day.date, next_open).as_str());
+                    tokio::time::sleep(CLOSED_POLL_INTERVAL).await;
                     continue;
                 }
-                let progress = (symbol_index as f64 / SYMBOLS.len() as f64) * 100.0;
-                let progress = (progress * 10.0).round() / 10.0;
-                let progress_string = format!("{}%", progress);
-                let title = format!("RVAT Scanner {} ({}) {}", &analysis_day.date.as_str(), 
-                                    loops_ptr.lock().unwrap(), progress_string);
-                app_clone.lock().unwrap().set_title(title.as_str());
-                let mut volumes:Vec<u64> = Vec::new();
-                for reference_day in &reference_days {
-                    let key = format!("{}.json", reference_day.date);
-                    let bar_data_path = format!("cache/{}/{}", symbol, key);
-                    let bar_data = match fs::read_to_string(bar_data_path.clone()) {
-                        Ok(bar_data) => bar_data,
-                        Err(_) => {
-                            continue;
-                        }
-                    };
-                    let bars:Vec<Bar> = match serde_json::from_str(&bar_data) {
-                        Ok(bars) => bars,
-                        Err(_) => {
-                            continue;
-                        }
-                    };
-                    let utc_hour = chrono::Utc::now().hour();
-                    let utc_minute = chrono::Utc::now().minute();
-                    let mut volume:u64 = 0;
-                    for bar in bars {
-                        let bar_hour = bar.t.hour();
-                        let bar_minute = bar.t.minute();
-                        if bar_hour < utc_hour {
-                            match bar.v.as_u64() {
-                                Some(v) => {
-                                    volume += v as u64;
-                                },
-                                None => {
-                                    println!("volume is not an u64");
-                                }
-                            }
-                        }
-                        if bar_hour == utc_hour && bar_minute <= utc_minute {
-                            match bar.v.as_u64() {
-                                Some(v) => {
-                                    volume += v as u64;
-                                },
-                                None => {
-                                    println!("volume is not an u64");
-                                }
-                            }
+                Some(phase)
+            },
+            Err(_) => None,
+        };
+        let phase = phase.map(|phase| format!(" {}", phase)).unwrap_or_default();
+        let completed = Arc::new(AtomicUsize::new(0));
+        let mut tasks = JoinSet::new();
+        for batch in symbols.chunks(BATCH_SIZE) {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let app = app.clone();
+            let client = client.clone();
+            let context = context.clone();
+            let failures = failures.clone();
+            let completed = completed.clone();
+            let batch = batch.to_vec();
+            let total = symbols.len();
+            let phase = phase.clone();
+            tasks.spawn(async move {
+                let batch_len = batch.len();
+                let result = scan_batch(&client, batch, context.clone()).await;
+                drop(permit);
+                let mut app = app.lock().unwrap();
+                match result {
+                    Ok(analyses) => {
+                        for analysis in analyses {
+                            app.add_analysis(analysis);
                         }
+                    },
+                    Err(_) => {
+                        failures.fetch_add(batch_len, Ordering::Relaxed);
                     }
-                    volumes.push(volume);
                 }
+                let done = completed.fetch_add(batch_len, Ordering::Relaxed) + batch_len;
+                let progress = (done as f64 / total as f64) * 100.0;
+                let progress = (progress * 10.0).round() / 10.0;
+                let progress_string = format!("{}%", progress);
+                let failures = failures.load(Ordering::Relaxed);
+                let title = if failures > 0 {
+                    format!("RVAT Scanner {} {}{} ({}) {} {} failed", context.feed,
+                            context.analysis_day.date.as_str(), phase, loops, progress_string, failures)
+                } else {
+                    format!("RVAT Scanner {} {}{} ({}) {}", context.feed,
+                            context.analysis_day.date.as_str(), phase, loops, progress_string)
+                };
+                app.set_title(title.as_str());
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+        loops += 1;
+    }
+}
+
+// reference averages for every symbol as of one session minute. they only
+// move when the minute does, so the stream reads the cache once a minute
+// instead of once per bar.
+#[derive(Default)]
+struct ReferenceAverages {
+    minute:Option<i64>,
+    averages:HashMap<String, f64>,
+}
 
-                let average_dvat:f64 = volumes.iter().sum::<u64>() as f64 / volumes.len() as f64;
-                let mut session_open_new_york_time:String = analysis_day.session_open.clone();
-                session_open_new_york_time.insert(2, ':');
-                let mut session_close_new_york_time = analysis_day.session_close.clone();
-                session_close_new_york_time.insert(2, ':');
-                let analysis_day_bars = alpaca::get_bars( symbol.as_str(),
-                                                          "1Min",
-                                                          time_in_new_york(session_open_new_york_time.as_str()),
-                                                          time_in_new_york(session_close_new_york_time.as_str()),
-                                                          "1000");
-
-                let mut analysis_dvat:u64 = 0;
-                for bar in analysis_day_bars.get_bars() {
-                    match bar.v.as_u64() {
-                        Some(v) => {
-                            analysis_dvat += v as u64;
-                        },
-                        None => {
-                            println!("volume is not an u64");
+impl ReferenceAverages {
+    async fn refresh(&mut self, context:&Arc<ScanContext>) {
+        let minute = session_minute(context);
+        if self.minute == Some(minute) {
+            return;
+        }
+        let reference_context = context.clone();
+        self.averages = tokio::task::spawn_blocking(move || {
+            reference_context.symbols.iter()
+                .filter_map(|symbol| {
+                    let average_dvat = average_reference_volume(symbol, &reference_context, minute)?;
+                    (average_dvat >= 1000.0).then(|| (symbol.clone(), average_dvat))
+                })
+                .collect()
+        }).await.unwrap_or_default();
+        self.minute = Some(minute);
+    }
+}
+
+// fill in today's bars from `start` to now, and the snapshots, over rest.
+// batches that fail get one more go after the rest are in. returns how many
+// symbols still couldn't be fetched.
+async fn backfill(client:&AlpacaClient, symbols:&[String], start:DateTime<FixedOffset>,
+                  volumes:&mut CumulativeVolume, snapshots:&mut HashMap<String, Snapshot>) -> usize {
+    let now:DateTime<FixedOffset> = chrono::Utc::now().into();
+    if start >= now {
+        return 0;
+    }
+    let mut pending:Vec<&[String]> = symbols.chunks(BATCH_SIZE).collect();
+    for _ in 0..2 {
+        let mut failed = Vec::new();
+        for batch in pending {
+            // the same minute applied twice counts once, so a retried batch
+            // can't double count
+            match client.get_multi_bars(batch, Timeframe::OneMin, start, now, Limit::MAX, Adjustment::All).await {
+                Ok(bars) => {
+                    for (symbol, bars) in bars {
+                        for bar in &bars {
+                            volumes.apply(&symbol, bar);
                         }
                     }
-                }
-                // find the % change from the 0th bar to the last bar
-                let mut pnl_change_percent:f64 = 0.0;
-                if analysis_day_bars.get_bars().len() == 0 {
+                },
+                Err(_) => {
+                    failed.push(batch);
                     continue;
                 }
-                let first_bar = &analysis_day_bars.get_bars()[0].c;
-                let last_bar =  &analysis_day_bars.get_bars()[analysis_day_bars.get_bars().len() - 1].c;
-                match first_bar.as_f64() {
-                    Some(first_bar) => {
-                        match last_bar.as_f64() {
-                            Some(last_bar) => {
-                                pnl_change_percent = (first_bar - last_bar) / first_bar;
-                            },
-                            None => { }
-                        }
-                    },
-                    None => { }
-                }
-                /*
-                 * where do you cut off average_dvat?
-                 * this value is the average of the last 17 days
-                 * if it's absurdly low and the stock is highly illiquid,
-                 * we get a false positive high score.
-                 * a score of 35513855 / 16164 = 2195.5 is absurdly high and 
-                 * what we are looking for.
-                 *
-                 * 61000 / 20 = 3005 is a better score but it's because the 
-                 * divisor is so low
-                 *
-                 * let's start with 350
-                 * now trying 1000
-                 */
-                if average_dvat < 1000 as f64 {
-                    continue;
-                }
-                if analysis_dvat == 0 as u64 {
-                    continue;
+            }
+            match client.get_snapshots(batch).await {
+                Ok(batch_snapshots) => snapshots.extend(batch_snapshots),
+                Err(_) => failed.push(batch),
+            }
+        }
+        pending = failed;
+    }
+    pending.iter().map(|batch| batch.len()).sum()
+}
+
+// score one symbol from the stream's running volume
+fn stream_analysis(symbol:String, average_dvat:f64, volumes:&CumulativeVolume,
+                   snapshots:&HashMap<String, Snapshot>, session:NaiveDate) -> Option<Analysis> {
+    let earliest_close = volumes.first_bar(&symbol)?.c;
+    let latest_close = volumes.last_bar(&symbol)?.c;
+    let analysis_dvat = volumes.volume(&symbol);
+    let mut analysis = score(symbol, average_dvat, analysis_dvat, earliest_close, latest_close)?;
+    if let Some(snapshot) = snapshots.get(&analysis.symbol) {
+        analysis = analysis.with_snapshot(snapshot, session);
+    }
+    analysis.last_price = Some(latest_close);
+    Some(analysis)
+}
+
+// backfill today's volume over rest once, then keep it current from the
+// websocket as each minute closes. a dropped socket is reconnected and the
+// gap backfilled. falls back to polling if the stream can't be opened.
+async fn stream_market(app:Arc<Mutex<App>>, client:AlpacaClient, context:ScanContext) {
+    let options = match StreamOptions::from_env(context.feed) {
+        Ok(options) => options,
+        Err(e) => {
+            app.lock().unwrap().set_title(format!("RVAT Scanner {} stream failed ({}), polling", context.feed, e).as_str());
+            return scan_market(app, client, context).await;
+        }
+    };
+    let stream = match BarStream::connect(&options).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            app.lock().unwrap().set_title(format!("RVAT Scanner {} stream failed ({}), polling", context.feed, e).as_str());
+            return scan_market(app, client, context).await;
+        }
+    };
+    // the socket drains on its own task so bars that close while a backfill
+    // runs wait in the channel instead of backing up on the server
+    let mut events = stream::spawn_reconnecting(stream, options, RetryPolicy::from_env());
+
+    let mut context = Arc::new(context);
+    let mut universe:HashSet<String> = context.symbols.iter().cloned().collect();
+    // today's volume so far, from the backfill and then the stream
+    let mut volumes = CumulativeVolume::new();
+    // prices and spreads as of the backfill, the last price then follows the stream
+    let mut snapshots:HashMap<String, Snapshot> = HashMap::new();
+    let mut averages = ReferenceAverages::default();
+    let title = |context:&ScanContext, status:&str, failures:usize| {
+        let phase = session::phase_at(chrono::Utc::now(), &context.calendar);
+        if failures > 0 {
+            format!("RVAT Scanner {} {} {} {} {} failed", context.feed, context.analysis_day.date, phase, status, failures)
+        } else {
+            format!("RVAT Scanner {} {} {} {}", context.feed, context.analysis_day.date, phase, status)
+        }
+    };
+
+    let mut failures = backfill(&client, &context.symbols, context.session_open, &mut volumes, &mut snapshots).await;
+    let mut changed:HashSet<String> = context.symbols.iter().cloned().collect();
+    let mut status = "live".to_string();
+    loop {
+        // a run left going overnight starts over on the new session
+        if let Some(next) = next_context(&app, &client, &context).await {
+            context = Arc::new(next);
+            universe = context.symbols.iter().cloned().collect();
+            volumes = CumulativeVolume::new();
+            snapshots.clear();
+            averages = ReferenceAverages::default();
+            failures = backfill(&client, &context.symbols, context.session_open, &mut volumes, &mut snapshots).await;
+            changed = context.symbols.iter().cloned().collect();
+        }
+        averages.refresh(&context).await;
+        {
+            let mut app = app.lock().unwrap();
+            for symbol in changed.drain() {
+                let average_dvat = averages.averages.get(&symbol).copied();
+                if let Some(analysis) = average_dvat.and_then(|average_dvat| {
+                    stream_analysis(symbol, average_dvat, &volumes, &snapshots, context.session)
+                }) {
+                    app.add_analysis(analysis);
                 }
-                app_clone.lock().unwrap().add_analysis(Analysis {
-                    symbol:String::from(symbol),
-                    average_dvat:average_dvat as u64,
-                    analysis_dvat:analysis_dvat as u64,
-                    score:analysis_dvat as f64 / average_dvat as f64,
-                    pnl_change_percent,
-                    created_at:chrono::Utc::now().into()
-                });
             }
-        });
+            app.set_title(title(&context, &status, failures).as_str());
+        }
+
+        // wait for the next event, then take whatever else has queued up so
+        // a burst of bars at the top of the minute is scored in one go. the
+        // wait is capped so a quiet night still notices the new session.
+        let mut queued = match tokio::time::timeout(CLOSED_POLL_INTERVAL, events.recv()).await {
+            Ok(Some(event)) => vec![event],
+            Ok(None) => break,
+            Err(_) => continue,
+        };
+        while let Ok(event) = events.try_recv() {
+            queued.push(event);
+        }
+        for event in queued {
+            match event {
+                StreamEvent::Bar(minute_bar) => {
+                    if !universe.contains(&minute_bar.symbol) {
+                        continue;
+                    }
+                    volumes.apply(&minute_bar.symbol, &minute_bar.bar);
+                    status = format!("live {}", minute_bar.bar.t.with_timezone(&New_York).format("%H:%M"));
+                    changed.insert(minute_bar.symbol);
+                },
+                StreamEvent::Disconnected(e) => {
+                    status = format!("reconnecting ({})", e);
+                    app.lock().unwrap().set_title(title(&context, &status, failures).as_str());
+                },
+                StreamEvent::Reconnected => {
+                    // pick up the minutes that closed while the socket was down
+                    let since = context.symbols.iter()
+                        .filter_map(|symbol| volumes.last_bar(symbol).map(|bar| bar.t))
+                        .max()
+                        .unwrap_or(context.session_open);
+                    failures = backfill(&client, &context.symbols, since, &mut volumes, &mut snapshots).await;
+                    status = "live".to_string();
+                    changed.extend(context.symbols.iter().cloned());
+                },
+            }
+        }
+    }
+    app.lock().unwrap().set_title(format!("RVAT Scanner {} {} stream closed", context.feed, context.analysis_day.date).as_str());
+}
+
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    /*mut */app: Arc<Mutex<App>>,
+    tick_rate: Duration,
+    runtime: Handle,
+    client: AlpacaClient,
+    options: ScanOptions,
+) -> io::Result<()> {
+    let context = runtime.block_on(scan_context(&client, options.universe, options.short_sessions))?;
+    // the scan runs on the runtime's worker threads, this thread only draws
+    if options.streaming {
+        runtime.spawn(stream_market(app.clone(), client, context));
+    } else {
+        runtime.spawn(scan_market(app.clone(), client, context));
     }
 
     let mut last_tick = Instant::now();
@@ -436,10 +897,10 @@ fn run_app<B: Backend>(
 fn duration_to_human_readable(dur:chrono::Duration) -> String {
     let hours = dur.num_hours();
     if hours >= 1 {
-        return format!("{}h", hours);
+        format!("{}h", hours)
     }
     else {
-        return format!("{}m", dur.num_minutes());
+        format!("{}m", dur.num_minutes())
     }
 }
 
@@ -453,7 +914,14 @@ fn count_to_human_readable(arg:u64) -> String {
     if arg > 1000 {
         return format!("{:.2}K", arg as f64 / 1000.0);
     }
-    return format!("{}", arg);
+    format!("{}", arg)
+}
+
+fn price_to_human_readable(price:Option<f64>) -> String {
+    match price {
+        Some(price) => format!("{:.2}", price),
+        None => String::from("-"),
+    }
 }
 
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
@@ -485,6 +953,10 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                 pnl_style,
             );
 
+            let daily_range = match &i.daily_bar {
+                Some(bar) => format!("{}-{}", price_to_human_readable(Some(bar.l)), price_to_human_readable(Some(bar.h))),
+                None => String::from("-"),
+            };
             let line_text = Spans::from(vec![
                 Span::raw(format!("{:<10} {:>8} {:>10} {:>8.2} {:>4}", 
                                   i.symbol, 
@@ -492,6 +964,11 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
                                   count_to_human_readable(i.average_dvat), 
                                   i.score, age_string)),
                 pnl_change_percent,
+                Span::raw(format!(" {:>8} {:>6} {:>8} {:>15}",
+                                  price_to_human_readable(i.last_price),
+                                  price_to_human_readable(i.spread),
+                                  price_to_human_readable(i.previous_close),
+                                  daily_range)),
             ]);
             ListItem::new(line_text).style(Style::default().fg(Color::White))
         })
@@ -499,13 +976,13 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
 
     let len = items.len();
     let (left_block, right_block) = if len > LIST_PAGE_SIZE && len <= LIST_PAGE_SIZE * 2 {
-        items.split_at(LIST_PAGE_SIZE as usize)
+        items.split_at(LIST_PAGE_SIZE)
     } else {
         (&items[..], &[] as &[ListItem])
     };
 
     let first_list = List::new(left_block)
-        .block(Block::default().borders(Borders::ALL).title(app.title.as_str()))
+        .block(Block::default().borders(Borders::ALL).title(app.title()))
         .highlight_style(
             Style::default()
                 .bg(Color::LightGreen)
@@ -523,7 +1000,7 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
 
 
     f.render_stateful_widget(first_list, chunks[0], &mut app.items.state);
-    if right_block.len() == 0 {
+    if right_block.is_empty() {
         return;
     }
     f.render_stateful_widget(second_list, chunks[1], &mut app.items.state);
@@ -0,0 +1,345 @@
+// where the market is in its day. the calendar says when each part of a
+// trading day starts and the clock says whether the market is really open,
+// which also covers closures the calendar didn't know about.
+use crate::alpaca::{Calendar, Clock};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::America::New_York;
+use chrono_tz::Tz;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    // from the extended session open (04:00) to the opening bell
+    PreMarket,
+    Regular,
+    // from the closing bell to the extended session close (20:00)
+    AfterHours,
+    // overnight, weekends and holidays
+    Closed,
+}
+
+impl fmt::Display for SessionPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SessionPhase::PreMarket => "pre-market",
+            SessionPhase::Regular => "regular",
+            SessionPhase::AfterHours => "after-hours",
+            SessionPhase::Closed => "closed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// `time` on the calendar `date` ("YYYY-MM-DD") in New York, with the offset
+// that applied on that date whatever the machine's own timezone is. the
+// calendar writes session hours as "HHMM" and bell times as "HH:MM", both are
+// accepted. None for unreadable input or a time the DST change skips or
+// repeats, which no session time ever is.
+pub fn new_york_time(date: &str, time: &str) -> Option<DateTime<Tz>> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H%M"))
+        .ok()?;
+    New_York.from_local_datetime(&date.and_time(time)).single()
+}
+
+// start and end of a day's extended session
+pub fn session_bounds(day: &Calendar) -> Option<(DateTime<Tz>, DateTime<Tz>)> {
+    Some((new_york_time(&day.date, &day.session_open)?, new_york_time(&day.date, &day.session_close)?))
+}
+
+// the phase at `now` going by the calendar alone. `calendar` can hold any
+// number of days, only today's (in New York) matters.
+pub fn phase_at(now: DateTime<Utc>, calendar: &[Calendar]) -> SessionPhase {
+    let today = now.with_timezone(&New_York).date_naive();
+    let day = match calendar.iter().find(|day| NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok() == Some(today)) {
+        Some(day) => day,
+        None => return SessionPhase::Closed,
+    };
+    let at = |time: &str| new_york_time(&day.date, time);
+    let times = (at(&day.session_open), at(&day.open), at(&day.close), at(&day.session_close));
+    let (session_open, open, close, session_close) = match times {
+        (Some(session_open), Some(open), Some(close), Some(session_close)) => (session_open, open, close, session_close),
+        _ => return SessionPhase::Closed,
+    };
+    if now < session_open || now >= session_close {
+        SessionPhase::Closed
+    } else if now < open {
+        SessionPhase::PreMarket
+    } else if now < close {
+        SessionPhase::Regular
+    } else {
+        SessionPhase::AfterHours
+    }
+}
+
+// the phase according to the clock, with the calendar filling in the
+// extended hours the clock doesn't report on
+pub fn session_phase(clock: &Clock, calendar: &[Calendar]) -> SessionPhase {
+    if clock.is_open {
+        return SessionPhase::Regular;
+    }
+    match phase_at(clock.timestamp.with_timezone(&Utc), calendar) {
+        // the bell hasn't rung after all, e.g. a closure the calendar missed
+        SessionPhase::Regular => SessionPhase::Closed,
+        phase => phase,
+    }
+}
+
+// the session to analyze and the sessions to compare it against
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    // the session under way, or the most recent one when between sessions
+    pub current: Calendar,
+    // exactly the requested number of sessions before `current`, most recent first
+    pub reference: Vec<Calendar>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    // no session on the calendar has started yet
+    NoSession,
+    NotEnoughSessions { needed: usize, found: usize },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSession => write!(f, "no trading session on the calendar has started"),
+            Self::NotEnoughSessions { needed, found } => write!(f, "needed {} reference sessions, the calendar has {}", needed, found),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+// calendar range that surely holds the current session and `reference_count`
+// sessions before it. the end runs a week past now so the current session is
+// there whatever the date is in UTC, and so are the sessions of the coming
+// days, see sessions_outdated.
+pub fn calendar_window(now: DateTime<Utc>, reference_count: usize) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+    // two calendar days per session covers weekends and any run of holidays
+    let start = now - Duration::days(reference_count as i64 * 2 + 10);
+    let end = now + Duration::days(7);
+    (start.fixed_offset(), end.fixed_offset())
+}
+
+// the current or most recent session in New York as of `now` and the
+// `reference_count` sessions before it. a session counts as current from its
+// extended open at 04:00, so at 02:00 on a tuesday it is still monday and on
+// a weekend or holiday it is the last day the market traded. `calendar` can
+// be in any order and run past today.
+pub fn resolve_sessions(now: DateTime<Utc>, calendar: &[Calendar], reference_count: usize) -> Result<Sessions, SessionError> {
+    let mut started: Vec<(NaiveDate, &Calendar)> = calendar.iter()
+        .filter_map(|day| {
+            let date = NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").ok()?;
+            let session_open = new_york_time(&day.date, &day.session_open)?;
+            (session_open <= now).then_some((date, day))
+        })
+        .collect();
+    started.sort_by_key(|(date, _)| std::cmp::Reverse(*date));
+    started.dedup_by_key(|(date, _)| *date);
+    let mut days = started.into_iter().map(|(_, day)| day.clone());
+    let current = days.next().ok_or(SessionError::NoSession)?;
+    let reference: Vec<Calendar> = days.take(reference_count).collect();
+    if reference.len() < reference_count {
+        return Err(SessionError::NotEnoughSessions { needed: reference_count, found: reference.len() });
+    }
+    Ok(Sessions { current, reference })
+}
+
+// whether resolve_sessions would pick another session than `current` at
+// `now`, i.e. a later day on `calendar` has reached its extended open. a
+// `now` past the last day of `calendar` can't be told apart, so it counts.
+pub fn sessions_outdated(current: &Calendar, now: DateTime<Utc>, calendar: &[Calendar]) -> bool {
+    let today = now.with_timezone(&New_York).date_naive().format("%Y-%m-%d").to_string();
+    if calendar.iter().all(|day| day.date < today) {
+        return true;
+    }
+    calendar.iter().any(|day| {
+        day.date > current.date && new_york_time(&day.date, &day.session_open).is_some_and(|open| open <= now)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calendar_window, new_york_time, phase_at, resolve_sessions, session_bounds, session_phase,
+                sessions_outdated, SessionError, SessionPhase};
+    use crate::alpaca::{Calendar, Clock};
+    use crate::test_support::{day, half_day, utc};
+    use chrono::{DateTime, Utc};
+
+    #[test]
+    fn phases_of_a_full_day() {
+        let calendar = vec![day("2023-07-05")];
+        assert_eq!(phase_at(utc("2023-07-05T03:59:00-04:00"), &calendar), SessionPhase::Closed);
+        assert_eq!(phase_at(utc("2023-07-05T04:00:00-04:00"), &calendar), SessionPhase::PreMarket);
+        assert_eq!(phase_at(utc("2023-07-05T09:29:59-04:00"), &calendar), SessionPhase::PreMarket);
+        assert_eq!(phase_at(utc("2023-07-05T09:30:00-04:00"), &calendar), SessionPhase::Regular);
+        assert_eq!(phase_at(utc("2023-07-05T16:00:00-04:00"), &calendar), SessionPhase::AfterHours);
+        assert_eq!(phase_at(utc("2023-07-05T20:00:00-04:00"), &calendar), SessionPhase::Closed);
+        // 21:30 in New York is already tomorrow in UTC
+        assert_eq!(phase_at(utc("2023-07-06T01:30:00Z"), &calendar), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn early_close_and_days_off() {
+        let calendar = vec![half_day("2023-11-24"), day("2023-11-22")];
+        assert_eq!(phase_at(utc("2023-11-24T13:30:00-05:00"), &calendar), SessionPhase::AfterHours);
+        assert_eq!(phase_at(utc("2023-11-24T17:30:00-05:00"), &calendar), SessionPhase::Closed);
+        // thanksgiving isn't on the calendar
+        assert_eq!(phase_at(utc("2023-11-23T10:00:00-05:00"), &calendar), SessionPhase::Closed);
+        // nor is a saturday
+        assert_eq!(phase_at(utc("2023-11-25T10:00:00-05:00"), &calendar), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn the_clock_has_the_last_word_on_the_regular_session() {
+        let calendar = vec![day("2023-07-05")];
+        let clock = |time: &str, is_open: bool| Clock {
+            timestamp: DateTime::parse_from_rfc3339(time).unwrap(),
+            is_open,
+            next_open: DateTime::parse_from_rfc3339("2023-07-06T09:30:00-04:00").unwrap(),
+            next_close: DateTime::parse_from_rfc3339("2023-07-05T16:00:00-04:00").unwrap(),
+        };
+        assert_eq!(session_phase(&clock("2023-07-05T11:00:00-04:00", true), &calendar), SessionPhase::Regular);
+        assert_eq!(session_phase(&clock("2023-07-05T11:00:00-04:00", false), &calendar), SessionPhase::Closed);
+        assert_eq!(session_phase(&clock("2023-07-05T07:00:00-04:00", false), &calendar), SessionPhase::PreMarket);
+        assert_eq!(session_phase(&clock("2023-07-05T18:00:00-04:00", false), &calendar), SessionPhase::AfterHours);
+    }
+
+    // late november 2023 around thanksgiving, oldest first like alpaca sends it
+    fn thanksgiving_week() -> Vec<Calendar> {
+        ["2023-11-16", "2023-11-17", "2023-11-20", "2023-11-21", "2023-11-22"].iter()
+            .map(|date| day(date))
+            .chain(std::iter::once(half_day("2023-11-24")))
+            .chain(["2023-11-27", "2023-11-28"].iter().map(|date| day(date)))
+            .collect()
+    }
+
+    fn dates(days: &[Calendar]) -> Vec<&str> {
+        days.iter().map(|day| day.date.as_str()).collect()
+    }
+
+    #[test]
+    fn resolves_during_a_session() {
+        let sessions = resolve_sessions(utc("2023-11-21T10:00:00-05:00"), &thanksgiving_week(), 3).unwrap();
+        assert_eq!(sessions.current.date, "2023-11-21");
+        assert_eq!(dates(&sessions.reference), vec!["2023-11-20", "2023-11-17", "2023-11-16"]);
+    }
+
+    #[test]
+    fn resolves_before_the_extended_open() {
+        let sessions = resolve_sessions(utc("2023-11-21T02:00:00-05:00"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(sessions.current.date, "2023-11-20");
+        let sessions = resolve_sessions(utc("2023-11-21T04:00:00-05:00"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(sessions.current.date, "2023-11-21");
+    }
+
+    #[test]
+    fn resolves_late_evening_when_utc_is_tomorrow() {
+        // 21:30 on the 21st in New York is the 22nd in UTC, and the calendar
+        // already lists the 22nd
+        let sessions = resolve_sessions(utc("2023-11-22T02:30:00Z"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(sessions.current.date, "2023-11-21");
+        assert_eq!(dates(&sessions.reference), vec!["2023-11-20", "2023-11-17"]);
+    }
+
+    #[test]
+    fn resolves_holidays_and_weekends() {
+        let thanksgiving = resolve_sessions(utc("2023-11-23T11:00:00-05:00"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(thanksgiving.current.date, "2023-11-22");
+        let saturday = resolve_sessions(utc("2023-11-25T11:00:00-05:00"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(saturday.current.date, "2023-11-24");
+        assert_eq!(dates(&saturday.reference), vec!["2023-11-22", "2023-11-21"]);
+        let sunday_night = resolve_sessions(utc("2023-11-26T23:00:00-05:00"), &thanksgiving_week(), 2).unwrap();
+        assert_eq!(sunday_night.current.date, "2023-11-24");
+    }
+
+    #[test]
+    fn sessions_go_out_of_date_at_the_next_open() {
+        let calendar = thanksgiving_week();
+        // midnight doesn't change the session, the next 04:00 open does
+        let tuesday = day("2023-11-21");
+        assert!(!sessions_outdated(&tuesday, utc("2023-11-22T00:01:00-05:00"), &calendar));
+        assert!(!sessions_outdated(&tuesday, utc("2023-11-22T03:59:00-05:00"), &calendar));
+        assert!(sessions_outdated(&tuesday, utc("2023-11-22T04:00:00-05:00"), &calendar));
+        // thanksgiving never opens
+        let wednesday = day("2023-11-22");
+        assert!(!sessions_outdated(&wednesday, utc("2023-11-23T11:00:00-05:00"), &calendar));
+        // nor does the weekend
+        let friday = half_day("2023-11-24");
+        assert!(!sessions_outdated(&friday, utc("2023-11-26T11:00:00-05:00"), &calendar));
+        assert!(sessions_outdated(&friday, utc("2023-11-27T04:00:00-05:00"), &calendar));
+        // past the end of the calendar there's no telling
+        let last = day("2023-11-28");
+        assert!(sessions_outdated(&last, utc("2023-11-29T00:01:00-05:00"), &calendar));
+    }
+
+    #[test]
+    fn needs_exactly_enough_reference_sessions() {
+        let sessions = resolve_sessions(utc("2023-11-22T10:00:00-05:00"), &thanksgiving_week(), 4).unwrap();
+        assert_eq!(sessions.reference.len(), 4);
+        assert_eq!(resolve_sessions(utc("2023-11-22T10:00:00-05:00"), &thanksgiving_week(), 5).unwrap_err(),
+                   SessionError::NotEnoughSessions { needed: 5, found: 4 });
+        assert_eq!(resolve_sessions(utc("2023-11-16T03:00:00-05:00"), &thanksgiving_week(), 0).unwrap_err(),
+                   SessionError::NoSession);
+    }
+
+    #[test]
+    fn window_covers_the_sessions() {
+        let now = utc("2023-11-25T11:00:00-05:00");
+        let (start, end) = calendar_window(now, 17);
+        assert!(end > now);
+        // 17 sessions plus the current one span about 26 calendar days
+        assert!(now - start.with_timezone(&Utc) >= chrono::Duration::days(30));
+    }
+
+    #[test]
+    fn new_york_time_uses_the_offset_of_the_date() {
+        let winter = new_york_time("2023-01-11", "0400").unwrap();
+        assert_eq!(winter.to_rfc3339(), "2023-01-11T04:00:00-05:00");
+        let summer = new_york_time("2023-07-05", "0400").unwrap();
+        assert_eq!(summer.to_rfc3339(), "2023-07-05T04:00:00-04:00");
+        // bell times are written with a colon
+        assert_eq!(new_york_time("2023-07-05", "09:30").unwrap().to_rfc3339(), "2023-07-05T09:30:00-04:00");
+    }
+
+    #[test]
+    fn new_york_time_across_dst_changes() {
+        // clocks spring forward at 02:00 on 2024-03-10 and fall back at 02:00
+        // on 2023-11-05, a 04:00 session open is after the change both times
+        assert_eq!(new_york_time("2024-03-10", "0400").unwrap().to_rfc3339(), "2024-03-10T04:00:00-04:00");
+        assert_eq!(new_york_time("2024-03-09", "2000").unwrap().to_rfc3339(), "2024-03-09T20:00:00-05:00");
+        assert_eq!(new_york_time("2023-11-05", "0400").unwrap().to_rfc3339(), "2023-11-05T04:00:00-05:00");
+        assert_eq!(new_york_time("2023-11-04", "2000").unwrap().to_rfc3339(), "2023-11-04T20:00:00-04:00");
+        // skipped and repeated local times have no single answer
+        assert!(new_york_time("2024-03-10", "0230").is_none());
+        assert!(new_york_time("2023-11-05", "0130").is_none());
+    }
+
+    #[test]
+    fn new_york_time_near_midnight() {
+        // the session close is late evening in New York but the next day in
+        // UTC, the date stays the calendar's
+        let close = new_york_time("2023-07-05", "2000").unwrap();
+        assert_eq!(close.with_timezone(&Utc).to_rfc3339(), "2023-07-06T00:00:00+00:00");
+        let midnight = new_york_time("2023-12-31", "0000").unwrap();
+        assert_eq!(midnight.with_timezone(&Utc).to_rfc3339(), "2023-12-31T05:00:00+00:00");
+    }
+
+    #[test]
+    fn new_york_time_rejects_bad_input() {
+        assert!(new_york_time("2023-02-30", "0400").is_none());
+        assert!(new_york_time("2023-07-05", "2500").is_none());
+        assert!(new_york_time("07/05/2023", "0400").is_none());
+        assert!(new_york_time("2023-07-05", "").is_none());
+    }
+
+    #[test]
+    fn session_bounds_of_a_day() {
+        let (open, close) = session_bounds(&half_day("2023-11-24")).unwrap();
+        assert_eq!(open.to_rfc3339(), "2023-11-24T04:00:00-05:00");
+        assert_eq!(close.to_rfc3339(), "2023-11-24T17:00:00-05:00");
+    }
+}
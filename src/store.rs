@@ -0,0 +1,563 @@
+// on disk format of the cache: one binary file per symbol, cache/<SYMBOL>.bars,
+// holding the 1 minute bars of every cached day in columns. the reader memory
+// maps the file, so summing a day's volume touches only the timestamp and
+// volume columns and never parses anything.
+//
+// layout, all little endian:
+//   header   magic "RVATBARS", version u32, day count u32, bar count u64,
+//            profile length u64. 32 bytes.
+//   days     one entry per day in date order: the date as days since
+//            0001-01-01 i32, index of its first bar u32, its bar count u32,
+//            its profile's session open as u32 minutes since the unix epoch
+//            (0 for no profile), index of its first profile minute u32, its
+//            profile length u32, 8 reserved bytes. 32 bytes each.
+//   columns  every bar in day order, one column after another: t as u32
+//            minutes since the unix epoch, o, h, l and c as f64, v and n as
+//            u64 and vw as f64.
+//   profiles each day's cumulative volume by minute of its session as u64,
+//            entry i being the volume of every bar up to and including
+//            minute i after the 04:00 open. a profile runs to the day's last
+//            bar, so "volume up to minute m" is one read.
+//
+// a day with no bars is still listed, so "cached, no trades" and "not cached"
+// stay different things. version 1 files have 16 byte day entries and no
+// profiles, they are still read and get rewritten on the next build.
+use crate::alpaca::{Bar, Calendar};
+use crate::session;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+static MAGIC: &[u8; 8] = b"RVATBARS";
+static VERSION: u32 = 2;
+static HEADER_LEN: usize = 32;
+static DAY_LEN: usize = 32;
+static DAY_LEN_V1: usize = 16;
+// alpaca opens every extended session at 04:00 New York time. a day the
+// calendar gives other hours is summed from the columns instead.
+static PROFILE_OPEN: &str = "0400";
+// a profile never needs to run past a whole day
+static PROFILE_MINUTES: i64 = 24 * 60;
+// bytes per bar across all columns: t, o, h, l, c, v, n, vw
+static BAR_LEN: usize = 4 + 8 * 7;
+
+// every cached day of one symbol, each day's bars in time order
+pub type History = BTreeMap<NaiveDate, Vec<Bar>>;
+
+pub fn path_for(cache_dir: &Path, symbol: &str) -> PathBuf {
+    cache_dir.join(format!("{}.bars", symbol))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn epoch_minute(bar: &Bar) -> io::Result<u32> {
+    u32::try_from(bar.t.timestamp().div_euclid(60))
+        .map_err(|_| invalid(format!("bar time {} can't be stored", bar.t)))
+}
+
+// the session open a day's profile counts from and its cumulative volume by
+// minute, or None when some bar falls outside the day and the profile couldn't
+// answer for it
+fn profile(date: NaiveDate, bars: &[Bar]) -> io::Result<Option<(u32, Vec<u64>)>> {
+    let open = match session::new_york_time(&date.format("%Y-%m-%d").to_string(), PROFILE_OPEN) {
+        Some(open) => open.timestamp().div_euclid(60),
+        None => return Ok(None),
+    };
+    let mut cumulative: Vec<u64> = Vec::new();
+    let mut volume = 0;
+    for bar in bars {
+        let minute = epoch_minute(bar)? as i64 - open;
+        if !(0..PROFILE_MINUTES).contains(&minute) || (minute as usize) < cumulative.len().saturating_sub(1) {
+            return Ok(None);
+        }
+        // carry the total forward over minutes without a bar
+        cumulative.resize(minute as usize + 1, volume);
+        volume += bar.v;
+        cumulative[minute as usize] = volume;
+    }
+    Ok(Some((open as u32, cumulative)))
+}
+
+pub fn encode(history: &History) -> io::Result<Vec<u8>> {
+    let bars: Vec<&Bar> = history.values().flatten().collect();
+    let day_count = u32::try_from(history.len()).map_err(|_| invalid("too many days".to_string()))?;
+    let mut profiles = Vec::with_capacity(history.len());
+    for (date, day_bars) in history {
+        profiles.push(profile(*date, day_bars)?);
+    }
+    let profile_len: usize = profiles.iter().flatten().map(|(_, minutes)| minutes.len()).sum();
+    let mut out = Vec::with_capacity(HEADER_LEN + history.len() * DAY_LEN + bars.len() * BAR_LEN + profile_len * 8);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&day_count.to_le_bytes());
+    out.extend_from_slice(&(bars.len() as u64).to_le_bytes());
+    out.extend_from_slice(&(profile_len as u64).to_le_bytes());
+    let mut first_bar: u32 = 0;
+    let mut first_minute: u32 = 0;
+    for ((date, day_bars), profile) in history.iter().zip(&profiles) {
+        let count = u32::try_from(day_bars.len()).map_err(|_| invalid(format!("too many bars on {}", date)))?;
+        let (open, minutes) = profile.as_ref().map_or((0, 0), |(open, minutes)| (*open, minutes.len() as u32));
+        out.extend_from_slice(&date.num_days_from_ce().to_le_bytes());
+        out.extend_from_slice(&first_bar.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&open.to_le_bytes());
+        out.extend_from_slice(&first_minute.to_le_bytes());
+        out.extend_from_slice(&minutes.to_le_bytes());
+        out.extend_from_slice(&[0; 8]);
+        first_bar += count;
+        first_minute += minutes;
+    }
+    for bar in &bars {
+        out.extend_from_slice(&epoch_minute(bar)?.to_le_bytes());
+    }
+    for column in [|b: &Bar| b.o, |b: &Bar| b.h, |b: &Bar| b.l, |b: &Bar| b.c] {
+        for bar in &bars {
+            out.extend_from_slice(&column(bar).to_le_bytes());
+        }
+    }
+    for bar in &bars {
+        out.extend_from_slice(&bar.v.to_le_bytes());
+    }
+    for bar in &bars {
+        out.extend_from_slice(&bar.n.to_le_bytes());
+    }
+    for bar in &bars {
+        out.extend_from_slice(&bar.vw.to_le_bytes());
+    }
+    for volume in profiles.iter().flatten().flat_map(|(_, minutes)| minutes) {
+        out.extend_from_slice(&volume.to_le_bytes());
+    }
+    Ok(out)
+}
+
+// write via a temp file and rename so an interrupted write never leaves a
+// truncated file behind
+pub fn write(path: &Path, history: &History) -> io::Result<()> {
+    let tmp = path.with_extension("bars.tmp");
+    fs::write(&tmp, encode(history)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DayEntry {
+    date: NaiveDate,
+    first_bar: usize,
+    bar_count: usize,
+    // epoch minute the profile counts from, 0 when the day has none
+    profile_open: u32,
+    first_minute: usize,
+    minutes: usize,
+}
+
+// a memory mapped .bars file, checked for a sane layout when opened
+#[derive(Debug)]
+pub struct BarFile {
+    data: Mmap,
+    version: u32,
+    days: Vec<DayEntry>,
+    bar_count: usize,
+}
+
+impl BarFile {
+    pub fn open(path: &Path) -> io::Result<BarFile> {
+        let file = File::open(path)?;
+        // safety: cache files are only ever replaced by rename, never
+        // written in place, so the mapped bytes don't change under us
+        let data = unsafe { Mmap::map(&file)? };
+        BarFile::parse(data).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    fn parse(data: Mmap) -> io::Result<BarFile> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err(invalid("not a bars file".to_string()));
+        }
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        let day_len = match version {
+            1 => DAY_LEN_V1,
+            2 => DAY_LEN,
+            _ => return Err(invalid(format!("unsupported version {}", version))),
+        };
+        let day_count = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+        let bar_count = u64::from_le_bytes(data[16..24].try_into().unwrap()) as usize;
+        let profile_len = match version {
+            1 => 0,
+            _ => u64::from_le_bytes(data[24..32].try_into().unwrap()) as usize,
+        };
+        let expected = day_count.checked_mul(day_len)
+            .and_then(|days| bar_count.checked_mul(BAR_LEN)?.checked_add(days))
+            .and_then(|body| profile_len.checked_mul(8)?.checked_add(body))
+            .and_then(|body| body.checked_add(HEADER_LEN));
+        if expected != Some(data.len()) {
+            return Err(invalid(format!("truncated, {} bytes for {} days and {} bars", data.len(), day_count, bar_count)));
+        }
+        let mut days = Vec::with_capacity(day_count);
+        let mut next_bar = 0;
+        let mut next_minute = 0;
+        for i in 0..day_count {
+            let entry = &data[HEADER_LEN + i * day_len..HEADER_LEN + (i + 1) * day_len];
+            let field = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+            let days_from_ce = i32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let date = NaiveDate::from_num_days_from_ce_opt(days_from_ce)
+                .ok_or_else(|| invalid(format!("bad date in day {}", i)))?;
+            let first_bar = field(4) as usize;
+            let count = field(8) as usize;
+            let (profile_open, first_minute, minutes) = match version {
+                1 => (0, next_minute, 0),
+                _ => (field(12), field(16) as usize, field(20) as usize),
+            };
+            if first_bar != next_bar || first_minute != next_minute
+                || days.last().is_some_and(|last: &DayEntry| last.date >= date) {
+                return Err(invalid(format!("day index out of order at {}", date)));
+            }
+            next_bar += count;
+            next_minute += minutes;
+            days.push(DayEntry { date, first_bar, bar_count: count, profile_open, first_minute, minutes });
+        }
+        if next_bar != bar_count {
+            return Err(invalid(format!("day index covers {} of {} bars", next_bar, bar_count)));
+        }
+        if next_minute != profile_len {
+            return Err(invalid(format!("day index covers {} of {} profile minutes", next_minute, profile_len)));
+        }
+        Ok(BarFile { data, version, days, bar_count })
+    }
+
+    // written by an older version without volume profiles, a build rewrites it
+    pub fn is_outdated(&self) -> bool {
+        self.version < VERSION
+    }
+
+    pub fn dates(&self) -> impl Iterator<Item = NaiveDate> + '_ {
+        self.days.iter().map(|day| day.date)
+    }
+
+    pub fn day(&self, date: NaiveDate) -> Option<DayBars<'_>> {
+        let index = self.days.binary_search_by_key(&date, |day| day.date).ok()?;
+        Some(DayBars { file: self, entry: self.days[index] })
+    }
+
+    // copy every day back out as bars, e.g. to add a day and write it again
+    pub fn history(&self) -> History {
+        self.days.iter()
+            .map(|entry| {
+                let day = DayBars { file: self, entry: *entry };
+                (entry.date, (0..day.len()).map(|i| day.bar(i)).collect())
+            })
+            .collect()
+    }
+
+    // start of column `index` in the order t, o, h, l, c, v, n, vw, and the
+    // profiles as column 8
+    fn column(&self, index: usize) -> usize {
+        let day_len = if self.version == 1 { DAY_LEN_V1 } else { DAY_LEN };
+        let columns = HEADER_LEN + self.days.len() * day_len;
+        if index == 0 {
+            columns
+        } else {
+            columns + self.bar_count * 4 + (index - 1) * self.bar_count * 8
+        }
+    }
+
+    fn u32_at(&self, column: usize, bar: usize) -> u32 {
+        let at = self.column(column) + bar * 4;
+        u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, column: usize, bar: usize) -> u64 {
+        let at = self.column(column) + bar * 8;
+        u64::from_le_bytes(self.data[at..at + 8].try_into().unwrap())
+    }
+
+    fn f64_at(&self, column: usize, bar: usize) -> f64 {
+        f64::from_bits(self.u64_at(column, bar))
+    }
+}
+
+// one day's bars inside a BarFile
+pub struct DayBars<'a> {
+    file: &'a BarFile,
+    entry: DayEntry,
+}
+
+impl DayBars<'_> {
+    pub fn len(&self) -> usize {
+        self.entry.bar_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entry.bar_count == 0
+    }
+
+    // minutes since the unix epoch of bar `i`
+    pub fn minute(&self, i: usize) -> u32 {
+        self.file.u32_at(0, self.entry.first_bar + i)
+    }
+
+    pub fn volume(&self, i: usize) -> u64 {
+        self.file.u64_at(5, self.entry.first_bar + i)
+    }
+
+    pub fn bar(&self, i: usize) -> Bar {
+        let at = self.entry.first_bar + i;
+        Bar {
+            t: Utc.timestamp_opt(self.file.u32_at(0, at) as i64 * 60, 0).unwrap().fixed_offset(),
+            o: self.file.f64_at(1, at),
+            h: self.file.f64_at(2, at),
+            l: self.file.f64_at(3, at),
+            c: self.file.f64_at(4, at),
+            v: self.file.u64_at(5, at),
+            n: self.file.u64_at(6, at),
+            vw: self.file.f64_at(7, at),
+        }
+    }
+
+    pub fn total_volume(&self) -> u64 {
+        (0..self.len()).map(|i| self.volume(i)).sum()
+    }
+
+    // the same sum as volume::volume_through_minute. one read from the
+    // day's volume profile, or summed off the columns when the day has no
+    // profile for `day`'s session open.
+    pub fn volume_through_minute(&self, day: &Calendar, minute: i64) -> Option<u64> {
+        let session_open: DateTime<Utc> = session::new_york_time(&day.date, &day.session_open)?.with_timezone(&Utc);
+        let open = session_open.timestamp().div_euclid(60);
+        let entry = &self.entry;
+        if entry.profile_open != 0 && entry.profile_open as i64 == open && minute >= 0 {
+            if entry.minutes == 0 {
+                return Some(0);
+            }
+            let at = (minute as u64).min(entry.minutes as u64 - 1) as usize;
+            return Some(self.file.u64_at(8, entry.first_minute + at));
+        }
+        let last = open.saturating_add(minute);
+        Some((0..self.len())
+            .take_while(|i| (self.minute(*i) as i64) <= last)
+            .map(|i| self.volume(i))
+            .sum())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MigrationSummary {
+    pub symbols: usize,
+    pub days: usize,
+    // json files that couldn't be read, they are fetched again on the next build
+    pub unreadable: usize,
+}
+
+// whether `cache_dir` still has symbol folders from the old
+// cache/<SYMBOL>/<date>.json layout
+pub fn needs_migration(cache_dir: &Path) -> bool {
+    match fs::read_dir(cache_dir) {
+        Ok(entries) => entries.flatten().any(|entry| entry.path().is_dir()),
+        Err(_) => false,
+    }
+}
+
+// fold each cache/<SYMBOL>/<date>.json folder into cache/<SYMBOL>.bars. a
+// folder is only removed once its .bars file has been written and read back.
+pub fn migrate_json(cache_dir: &Path) -> io::Result<MigrationSummary> {
+    let mut summary = MigrationSummary::default();
+    for entry in fs::read_dir(cache_dir)? {
+        let folder = entry?.path();
+        if !folder.is_dir() {
+            continue;
+        }
+        let symbol = match folder.file_name().and_then(|name| name.to_str()) {
+            Some(symbol) => symbol.to_string(),
+            None => continue,
+        };
+        let path = path_for(cache_dir, &symbol);
+        let mut history = match BarFile::open(&path) {
+            Ok(file) => file.history(),
+            Err(_) => History::new(),
+        };
+        for file in fs::read_dir(&folder)? {
+            let file = file?.path();
+            if file.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let date = file.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok());
+            let bars = fs::read_to_string(&file).ok()
+                .and_then(|json| serde_json::from_str::<Vec<Bar>>(&json).ok());
+            match (date, bars) {
+                (Some(date), Some(mut bars)) => {
+                    bars.sort_by_key(|bar| bar.t);
+                    history.insert(date, bars);
+                    summary.days += 1;
+                },
+                _ => summary.unreadable += 1,
+            }
+        }
+        write(&path, &history)?;
+        BarFile::open(&path)?;
+        fs::remove_dir_all(&folder)?;
+        summary.symbols += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, migrate_json, needs_migration, path_for, write, BarFile, History};
+    use crate::alpaca::{Bar, Calendar};
+    use crate::test_support::{bar, date, day as calendar_day, temp_dir};
+    use std::fs;
+
+    fn history() -> History {
+        let mut history = History::new();
+        history.insert(date("2023-11-22"), vec![
+            bar("2023-11-22T09:00:00Z", 100),
+            bar("2023-11-22T09:01:00Z", 200),
+            bar("2023-11-22T14:30:00Z", 5000),
+        ]);
+        history.insert(date("2023-11-24"), vec![]);
+        history.insert(date("2023-11-27"), vec![bar("2023-11-27T09:00:00Z", 300)]);
+        history
+    }
+
+    #[test]
+    fn round_trips_every_field() {
+        let dir = temp_dir("round-trip");
+        let path = path_for(&dir, "AAPL");
+        write(&path, &history()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, encode(&history()).unwrap().len());
+        let file = BarFile::open(&path).unwrap();
+        assert_eq!(file.history(), history());
+        assert_eq!(file.dates().collect::<Vec<_>>(), vec![date("2023-11-22"), date("2023-11-24"), date("2023-11-27")]);
+        let thanksgiving_friday = file.day(date("2023-11-24")).unwrap();
+        assert!(thanksgiving_friday.is_empty(), "a cached day with no trades is still there");
+        assert!(file.day(date("2023-11-23")).is_none());
+        assert_eq!(file.day(date("2023-11-22")).unwrap().total_volume(), 5300);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sums_volume_off_the_columns() {
+        let dir = temp_dir("volume");
+        let path = path_for(&dir, "AAPL");
+        write(&path, &history()).unwrap();
+        let file = BarFile::open(&path).unwrap();
+        let day = calendar_day("2023-11-22");
+        let bars = file.day(date("2023-11-22")).unwrap();
+        // 04:00 EST is 09:00Z
+        assert_eq!(bars.volume_through_minute(&day, -1), Some(0));
+        assert_eq!(bars.volume_through_minute(&day, 0), Some(100));
+        assert_eq!(bars.volume_through_minute(&day, 1), Some(300));
+        assert_eq!(bars.volume_through_minute(&day, 329), Some(300));
+        assert_eq!(bars.volume_through_minute(&day, 330), Some(5300));
+        assert_eq!(bars.volume_through_minute(&day, i64::MAX), Some(5300));
+        let all: Vec<Bar> = history()[&date("2023-11-22")].clone();
+        assert_eq!(crate::volume::volume_through_minute(&day, &all, 1), Some(300));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profiles_match_summing_the_bars() {
+        let dir = temp_dir("profile");
+        let path = path_for(&dir, "AAPL");
+        let mut history = history();
+        // a half day with a gap, two bars in one minute and a late print
+        history.insert(date("2023-11-24"), vec![
+            bar("2023-11-24T09:05:00Z", 10),
+            bar("2023-11-24T14:30:00Z", 20),
+            bar("2023-11-24T14:30:00Z", 30),
+            bar("2023-11-24T21:59:00Z", 40),
+        ]);
+        write(&path, &history).unwrap();
+        let file = BarFile::open(&path).unwrap();
+        assert!(!file.is_outdated());
+        for (day, bars) in &history {
+            let day = calendar_day(&day.to_string());
+            let cached = file.day(date(&day.date)).unwrap();
+            for minute in (-2..1100).chain([i64::MAX]) {
+                assert_eq!(cached.volume_through_minute(&day, minute),
+                           crate::volume::volume_through_minute(&day, bars, minute),
+                           "{} minute {}", day.date, minute);
+            }
+        }
+        // a session opening at some other time can't use the profile
+        let late = Calendar { session_open: "0500".to_string(), ..calendar_day("2023-11-22") };
+        assert_eq!(file.day(date("2023-11-22")).unwrap().volume_through_minute(&late, 0), Some(300));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // the version 1 layout, 16 byte day entries and no profiles
+    fn encode_v1(history: &History) -> Vec<u8> {
+        let v2 = encode(history).unwrap();
+        let bars: usize = history.values().map(|bars| bars.len()).sum();
+        let columns = 32 + history.len() * 32;
+        let mut out = Vec::new();
+        out.extend_from_slice(&v2[..8]);
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&v2[12..24]);
+        out.extend_from_slice(&[0; 8]);
+        for day in 0..history.len() {
+            out.extend_from_slice(&v2[32 + day * 32..32 + day * 32 + 12]);
+            out.extend_from_slice(&[0; 4]);
+        }
+        out.extend_from_slice(&v2[columns..columns + bars * 60]);
+        out
+    }
+
+    #[test]
+    fn reads_files_without_profiles() {
+        let dir = temp_dir("v1");
+        let path = path_for(&dir, "AAPL");
+        fs::write(&path, encode_v1(&history())).unwrap();
+        let file = BarFile::open(&path).unwrap();
+        assert!(file.is_outdated());
+        assert_eq!(file.history(), history());
+        let day = file.day(date("2023-11-22")).unwrap();
+        assert_eq!(day.volume_through_minute(&calendar_day("2023-11-22"), 1), Some(300));
+        assert_eq!(day.volume_through_minute(&calendar_day("2023-11-22"), 330), Some(5300));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let dir = temp_dir("damaged");
+        let path = path_for(&dir, "AAPL");
+        let bytes = encode(&history()).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(BarFile::open(&path).unwrap_err().to_string().contains("truncated"));
+        fs::write(&path, b"[{\"t\":\"2023-11-22T09:00:00Z\"}]").unwrap();
+        assert!(BarFile::open(&path).unwrap_err().to_string().contains("not a bars file"));
+        let mut wrong_version = bytes.clone();
+        wrong_version[8] = 9;
+        fs::write(&path, &wrong_version).unwrap();
+        assert!(BarFile::open(&path).unwrap_err().to_string().contains("version"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_the_json_layout() {
+        let dir = temp_dir("migrate");
+        fs::create_dir_all(dir.join("AAPL")).unwrap();
+        fs::create_dir_all(dir.join("MSFT")).unwrap();
+        for (day, bars) in history() {
+            let json = serde_json::to_string(&bars).unwrap();
+            fs::write(dir.join("AAPL").join(format!("{}.json", day)), json).unwrap();
+        }
+        fs::write(dir.join("MSFT").join("2023-11-22.json"), "[{\"t\":").unwrap();
+        fs::write(dir.join("MSFT").join("2023-11-27.json"), "[]").unwrap();
+        assert!(needs_migration(&dir));
+
+        let summary = migrate_json(&dir).unwrap();
+        assert_eq!(summary.symbols, 2);
+        assert_eq!(summary.days, 4);
+        assert_eq!(summary.unreadable, 1);
+        assert!(!needs_migration(&dir));
+        assert_eq!(BarFile::open(&path_for(&dir, "AAPL")).unwrap().history(), history());
+        let msft = BarFile::open(&path_for(&dir, "MSFT")).unwrap();
+        assert_eq!(msft.dates().collect::<Vec<_>>(), vec![date("2023-11-27")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
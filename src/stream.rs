@@ -0,0 +1,447 @@
+// client for alpaca's real time market data websocket. subscribes to minute
+// bars, and CumulativeVolume keeps the running volume per symbol so the
+// scanner can update as each minute closes instead of re-downloading the day.
+use crate::alpaca::{self, Bar, Feed, RetryPolicy};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub static DEFAULT_STREAM_BASE_URL: &str = "wss://stream.data.alpaca.markets";
+
+// market data stream host. override with APCA_STREAM_BASE_URL
+pub fn stream_base_url() -> String {
+    alpaca::load_base_url("APCA_STREAM_BASE_URL", DEFAULT_STREAM_BASE_URL)
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    // boxed, the tungstenite error is big enough to bloat every Result
+    Socket(Box<tokio_tungstenite::tungstenite::Error>),
+    // an error message from alpaca, e.g. 402 auth failed or 406 connection
+    // limit exceeded
+    Server { code: u16, message: String },
+    // the server said something we didn't expect during the handshake
+    Protocol(String),
+    // options that can't work, e.g. a feed with no stream
+    Config(String),
+    Closed,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socket(e) => write!(f, "websocket error: {}", e),
+            Self::Server { code, message } => write!(f, "stream error {}: {}", code, message),
+            Self::Protocol(message) => write!(f, "unexpected stream message: {}", message),
+            Self::Config(message) => write!(f, "stream config error: {}", message),
+            Self::Closed => write!(f, "stream closed"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<tokio_tungstenite::tungstenite::Error> for StreamError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Self::Socket(Box::new(e))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct StreamBar {
+    #[serde(rename = "S")]
+    symbol: String,
+    #[serde(flatten)]
+    bar: Bar,
+}
+
+// every frame is a json array of these, tagged by "T"
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "T")]
+enum StreamMessage {
+    #[serde(rename = "success")]
+    Success { msg: String },
+    #[serde(rename = "error")]
+    Error { code: u16, msg: String },
+    #[serde(rename = "subscription")]
+    Subscription,
+    #[serde(rename = "b")]
+    Bar(StreamBar),
+    // a late trade changed a minute bar that was already sent
+    #[serde(rename = "u")]
+    UpdatedBar(StreamBar),
+    #[serde(other)]
+    Other,
+}
+
+// a closed minute bar
+#[derive(Debug, Clone)]
+pub struct MinuteBar {
+    pub symbol: String,
+    pub bar: Bar,
+    // true when this replaces a bar sent earlier for the same minute
+    pub updated: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+struct SymbolVolume {
+    // volume by minute, so an updated bar replaces rather than adds
+    minutes: HashMap<i64, u64>,
+    total: u64,
+    first: Option<Bar>,
+    last: Option<Bar>,
+}
+
+// running volume per symbol built from minute bars, in any order and with
+// repeats. the same minute seen twice counts once, with the latest volume.
+#[derive(Debug, Default, Clone)]
+pub struct CumulativeVolume {
+    symbols: HashMap<String, SymbolVolume>,
+}
+
+impl CumulativeVolume {
+    pub fn new() -> CumulativeVolume {
+        CumulativeVolume::default()
+    }
+
+    // add or replace a minute, returning the symbol's new total
+    pub fn apply(&mut self, symbol: &str, bar: &Bar) -> u64 {
+        let entry = self.symbols.entry(symbol.to_string()).or_default();
+        let previous = entry.minutes.insert(bar.t.timestamp(), bar.v).unwrap_or(0);
+        entry.total = entry.total - previous + bar.v;
+        if entry.first.as_ref().is_none_or(|first| bar.t <= first.t) {
+            entry.first = Some(bar.clone());
+        }
+        if entry.last.as_ref().is_none_or(|last| bar.t >= last.t) {
+            entry.last = Some(bar.clone());
+        }
+        entry.total
+    }
+
+    pub fn volume(&self, symbol: &str) -> u64 {
+        self.symbols.get(symbol).map_or(0, |s| s.total)
+    }
+
+    // earliest and latest bar seen for the symbol
+    pub fn first_bar(&self, symbol: &str) -> Option<&Bar> {
+        self.symbols.get(symbol)?.first.as_ref()
+    }
+
+    pub fn last_bar(&self, symbol: &str) -> Option<&Bar> {
+        self.symbols.get(symbol)?.last.as_ref()
+    }
+}
+
+pub struct StreamOptions {
+    pub url: String,
+    pub key: String,
+    pub secret: String,
+    // symbols to subscribe to, "*" for every symbol
+    pub symbols: Vec<String>,
+    // also subscribe to corrections of bars already sent
+    pub updated_bars: bool,
+}
+
+impl StreamOptions {
+    // every symbol on `feed`, keys from the environment. alpaca only streams
+    // the sip and iex feeds.
+    pub fn from_env(feed: Feed) -> Result<StreamOptions, StreamError> {
+        if feed == Feed::Otc {
+            return Err(StreamError::Config(format!("the {} feed has no stream, use sip or iex", feed)));
+        }
+        let var = |key: &str| env::var(key).map_err(|_| StreamError::Config(format!("{} is not set", key)));
+        Ok(StreamOptions {
+            url: format!("{}/v2/{}", stream_base_url(), feed.as_str()),
+            key: var("APCA_API_KEY_ID")?,
+            secret: var("APCA_API_SECRET_KEY")?,
+            symbols: vec!["*".to_string()],
+            updated_bars: true,
+        })
+    }
+}
+
+pub struct BarStream {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pending: VecDeque<StreamMessage>,
+    // messages that couldn't be decoded and were dropped
+    skipped: usize,
+}
+
+impl BarStream {
+    // connect, authenticate and subscribe. returns once alpaca has confirmed
+    // the subscription so no bar after this point is missed.
+    pub async fn connect(options: &StreamOptions) -> Result<BarStream, StreamError> {
+        let (socket, _) = connect_async(options.url.as_str()).await?;
+        let mut stream = BarStream {
+            socket,
+            pending: VecDeque::new(),
+            skipped: 0,
+        };
+        stream.expect_success("connected").await?;
+        stream.send(json!({
+            "action": "auth",
+            "key": options.key,
+            "secret": options.secret,
+        })).await?;
+        stream.expect_success("authenticated").await?;
+        let mut subscribe = json!({ "action": "subscribe", "bars": options.symbols });
+        if options.updated_bars {
+            subscribe["updatedBars"] = json!(options.symbols);
+        }
+        stream.send(subscribe).await?;
+        match stream.next_message().await? {
+            StreamMessage::Subscription => Ok(stream),
+            other => Err(StreamError::Protocol(format!("{:?}", other))),
+        }
+    }
+
+    // how many messages couldn't be decoded and were dropped
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    // the next bar or updated bar. Err(Closed) once the server hangs up.
+    pub async fn next_bar(&mut self) -> Result<MinuteBar, StreamError> {
+        loop {
+            let (stream_bar, updated) = match self.next_message().await? {
+                StreamMessage::Bar(bar) => (bar, false),
+                StreamMessage::UpdatedBar(bar) => (bar, true),
+                StreamMessage::Error { code, msg } => return Err(StreamError::Server { code, message: msg }),
+                _ => continue,
+            };
+            return Ok(MinuteBar {
+                symbol: stream_bar.symbol,
+                bar: stream_bar.bar,
+                updated,
+            });
+        }
+    }
+
+    async fn send(&mut self, value: serde_json::Value) -> Result<(), StreamError> {
+        self.socket.send(Message::Text(value.to_string())).await?;
+        Ok(())
+    }
+
+    async fn next_message(&mut self) -> Result<StreamMessage, StreamError> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            let text = match self.socket.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Binary(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+                Some(Ok(Message::Close(_))) | None => return Err(StreamError::Closed),
+                // pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            };
+            // one bad message, or a frame that isn't json at all, is dropped
+            // on its own rather than ending the stream
+            let values: Vec<serde_json::Value> = match serde_json::from_str(&text) {
+                Ok(values) => values,
+                Err(_) => {
+                    self.skipped += 1;
+                    continue;
+                }
+            };
+            for value in values {
+                match serde_json::from_value::<StreamMessage>(value) {
+                    Ok(message) => self.pending.push_back(message),
+                    Err(_) => self.skipped += 1,
+                }
+            }
+        }
+    }
+
+    async fn expect_success(&mut self, expected: &str) -> Result<(), StreamError> {
+        match self.next_message().await? {
+            StreamMessage::Success { msg } if msg == expected => Ok(()),
+            StreamMessage::Error { code, msg } => Err(StreamError::Server { code, message: msg }),
+            other => Err(StreamError::Protocol(format!("{:?}", other))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamEvent {
+    Bar(MinuteBar),
+    // the socket dropped or a reconnect failed, another attempt follows
+    Disconnected(StreamError),
+    // subscribed again after a disconnect. bars that closed in between were
+    // missed and have to be fetched some other way.
+    Reconnected,
+}
+
+// read `stream` on its own task so bars wait in the channel instead of
+// backing up on the server. when the socket drops it reconnects and
+// resubscribes with `backoff` delays, for as long as the receiver is kept.
+pub fn spawn_reconnecting(mut stream: BarStream, options: StreamOptions, backoff: RetryPolicy) -> mpsc::UnboundedReceiver<StreamEvent> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let error = loop {
+                match stream.next_bar().await {
+                    Ok(bar) => {
+                        if sender.send(StreamEvent::Bar(bar)).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e) => break e,
+                }
+            };
+            if sender.send(StreamEvent::Disconnected(error)).is_err() {
+                return;
+            }
+            let mut attempt = 0;
+            stream = loop {
+                tokio::time::sleep(backoff.backoff(attempt)).await;
+                attempt = attempt.saturating_add(1);
+                match BarStream::connect(&options).await {
+                    Ok(stream) => break stream,
+                    Err(e) => {
+                        if sender.send(StreamEvent::Disconnected(e)).is_err() {
+                            return;
+                        }
+                    },
+                }
+            };
+            if sender.send(StreamEvent::Reconnected).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn_reconnecting, BarStream, CumulativeVolume, StreamError, StreamEvent, StreamOptions};
+    use crate::alpaca::{Feed, RetryPolicy};
+    use crate::test_support::MOCK_URL;
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn bar(kind: &str, minute: u32, volume: u64) -> String {
+        format!(r#"{{"T":"{}","S":"AAPL","t":"2023-01-11T14:{:02}:00Z","o":1.0,"h":1.0,"l":1.0,"c":1.0,"v":{},"n":1,"vw":1.0}}"#,
+                kind, minute, volume)
+    }
+
+    // a stand-in for alpaca's stream: one connection per entry of
+    // `connections`, each handshaking like the real thing, unless
+    // `reject_auth`, then sending its frames and hanging up
+    async fn mock_stream(reject_auth: bool, connections: Vec<Vec<String>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for frames in connections {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut socket = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                socket.send(Message::Text(r#"[{"T":"success","msg":"connected"}]"#.to_string())).await.unwrap();
+                let auth = socket.next().await.unwrap().unwrap().into_text().unwrap();
+                assert!(auth.contains(r#""action":"auth""#));
+                if reject_auth {
+                    socket.send(Message::Text(r#"[{"T":"error","code":402,"msg":"auth failed"}]"#.to_string())).await.unwrap();
+                    return;
+                }
+                socket.send(Message::Text(r#"[{"T":"success","msg":"authenticated"}]"#.to_string())).await.unwrap();
+                let subscribe = socket.next().await.unwrap().unwrap().into_text().unwrap();
+                assert!(subscribe.contains(r#""updatedBars":["*"]"#));
+                socket.send(Message::Text(r#"[{"T":"subscription","trades":[],"quotes":[],"bars":["*"],"updatedBars":["*"]}]"#.to_string())).await.unwrap();
+                for frame in frames {
+                    socket.send(Message::Text(frame)).await.unwrap();
+                }
+                socket.close(None).await.unwrap();
+            }
+        });
+        format!("ws://{}/v2/sip", addr)
+    }
+
+    fn options(url: String) -> StreamOptions {
+        StreamOptions {
+            url,
+            key: "test-key".to_string(),
+            secret: "test-secret".to_string(),
+            symbols: vec!["*".to_string()],
+            updated_bars: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_a_running_volume() {
+        let url = mock_stream(false, vec![vec![
+            format!("[{}]", bar("b", 30, 100)),
+            // a correction to the 14:30 bar replaces its volume
+            format!("[{},{}]", bar("u", 30, 150), bar("b", 31, 50)),
+        ]]).await;
+        let mut stream = BarStream::connect(&options(url)).await.unwrap();
+        let mut volumes = CumulativeVolume::new();
+        let first = stream.next_bar().await.unwrap();
+        assert_eq!((first.symbol.as_str(), first.updated), ("AAPL", false));
+        assert_eq!(volumes.apply(&first.symbol, &first.bar), 100);
+        let updated = stream.next_bar().await.unwrap();
+        assert!(updated.updated);
+        assert_eq!(volumes.apply(&updated.symbol, &updated.bar), 150);
+        let next = stream.next_bar().await.unwrap();
+        assert_eq!(volumes.apply(&next.symbol, &next.bar), 200);
+        assert_eq!(volumes.volume("AAPL"), 200);
+        assert_eq!(volumes.last_bar("AAPL").unwrap().v, 50);
+        assert!(matches!(stream.next_bar().await, Err(StreamError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn skips_messages_it_cannot_decode() {
+        let url = mock_stream(false, vec![vec![
+            "not json".to_string(),
+            format!(r#"[{{"T":"b","S":"AAPL","t":"yesterday"}},{}]"#, bar("b", 30, 100)),
+            format!("[{}]", bar("b", 31, 50)),
+        ]]).await;
+        let mut stream = BarStream::connect(&options(url)).await.unwrap();
+        assert_eq!(stream.next_bar().await.unwrap().bar.v, 100);
+        assert_eq!(stream.next_bar().await.unwrap().bar.v, 50);
+        assert_eq!(stream.skipped(), 2);
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_the_socket_drops() {
+        let url = mock_stream(false, vec![
+            vec![format!("[{}]", bar("b", 30, 100))],
+            vec![format!("[{}]", bar("b", 35, 70))],
+        ]).await;
+        let stream = BarStream::connect(&options(url.clone())).await.unwrap();
+        let backoff = RetryPolicy {
+            base_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+        let mut events = spawn_reconnecting(stream, options(url), backoff);
+        assert!(matches!(events.recv().await, Some(StreamEvent::Bar(bar)) if bar.bar.v == 100));
+        assert!(matches!(events.recv().await, Some(StreamEvent::Disconnected(StreamError::Closed))));
+        assert!(matches!(events.recv().await, Some(StreamEvent::Reconnected)));
+        assert!(matches!(events.recv().await, Some(StreamEvent::Bar(bar)) if bar.bar.v == 70));
+    }
+
+    #[test]
+    fn only_sip_and_iex_stream() {
+        lazy_static::initialize(&MOCK_URL);
+        assert!(StreamOptions::from_env(Feed::Iex).unwrap().url.ends_with("/v2/iex"));
+        assert!(matches!(StreamOptions::from_env(Feed::Otc), Err(StreamError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_auth_failure() {
+        let url = mock_stream(true, vec![vec![]]).await;
+        match BarStream::connect(&options(url)).await {
+            Err(StreamError::Server { code, .. }) => assert_eq!(code, 402),
+            Err(e) => panic!("expected auth failure, got {}", e),
+            Ok(_) => panic!("expected auth failure"),
+        }
+    }
+}
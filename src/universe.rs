@@ -0,0 +1,138 @@
+// picks the symbols to scan and cache from alpaca's asset list. delisted and
+// halted names drop out on their own since alpaca marks them inactive or
+// untradable, and `excluded_tickers.json` covers anything else to skip.
+use crate::alpaca::{AlpacaClient, AlpacaClientError, Asset, AssetClass, AssetStatus};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub static BIG_BOARD: [&str; 4] = ["ARCA", "NASDAQ", "NYSE", "BATS"];
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Ticker {
+    pub ticker: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct UniverseFilter {
+    // exchanges to keep, empty for every exchange
+    pub exchanges: Vec<String>,
+    pub require_shortable: bool,
+    pub require_easy_to_borrow: bool,
+    pub require_fractionable: bool,
+    // symbols never scanned, e.g. from excluded_tickers.json
+    pub excluded: HashSet<String>,
+}
+
+impl Default for UniverseFilter {
+    fn default() -> Self {
+        UniverseFilter {
+            exchanges: BIG_BOARD.iter().map(|e| e.to_string()).collect(),
+            require_shortable: false,
+            require_easy_to_borrow: false,
+            require_fractionable: false,
+            excluded: HashSet::new(),
+        }
+    }
+}
+
+impl UniverseFilter {
+    pub fn allows(&self, asset: &Asset) -> bool {
+        asset.class == AssetClass::UsEquity
+            && asset.status == AssetStatus::Active
+            && asset.tradable
+            && (self.exchanges.is_empty() || self.exchanges.iter().any(|e| e.eq_ignore_ascii_case(&asset.exchange)))
+            && (!self.require_shortable || asset.shortable)
+            && (!self.require_easy_to_borrow || asset.easy_to_borrow)
+            && (!self.require_fractionable || asset.fractionable)
+            && !self.excluded.contains(&asset.symbol)
+    }
+
+    // the allowed symbols, sorted so passes always run in the same order
+    pub fn select(&self, assets: &[Asset]) -> Vec<String> {
+        let mut symbols: Vec<String> = assets.iter()
+            .filter(|asset| self.allows(asset))
+            .map(|asset| asset.symbol.clone())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols
+    }
+}
+
+// the symbols listed in an excluded_tickers.json file. a missing file
+// excludes nothing.
+pub fn load_excluded(path: &Path) -> io::Result<HashSet<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    let tickers: Vec<Ticker> = serde_json::from_str(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(tickers.into_iter().map(|t| t.ticker).collect())
+}
+
+// fetch the asset list and apply `filter`
+pub async fn select(client: &AlpacaClient, filter: &UniverseFilter) -> Result<Vec<String>, AlpacaClientError> {
+    Ok(filter.select(&client.get_assets().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UniverseFilter;
+    use crate::alpaca::{Asset, AssetClass, AssetStatus};
+
+    fn asset(symbol: &str, exchange: &str) -> Asset {
+        Asset {
+            id: format!("id-{}", symbol),
+            class: AssetClass::UsEquity,
+            exchange: exchange.to_string(),
+            symbol: symbol.to_string(),
+            name: String::new(),
+            status: AssetStatus::Active,
+            tradable: true,
+            marginable: true,
+            shortable: true,
+            easy_to_borrow: true,
+            fractionable: true,
+        }
+    }
+
+    #[test]
+    fn drops_delisted_halted_and_off_board_names() {
+        let mut delisted = asset("GONE", "NYSE");
+        delisted.status = AssetStatus::Inactive;
+        let mut halted = asset("HALT", "NASDAQ");
+        halted.tradable = false;
+        let mut crypto = asset("BTC/USD", "CRYPTO");
+        crypto.class = AssetClass::Crypto;
+        let assets = vec![asset("MSFT", "NASDAQ"), asset("AAPL", "NASDAQ"), delisted, halted,
+                          asset("PINK", "OTC"), crypto, asset("SPY", "ARCA")];
+        assert_eq!(UniverseFilter::default().select(&assets), vec!["AAPL", "MSFT", "SPY"]);
+    }
+
+    #[test]
+    fn applies_configured_filters() {
+        let mut hard_to_borrow = asset("HTB", "NYSE");
+        hard_to_borrow.easy_to_borrow = false;
+        let mut whole_shares = asset("WHOLE", "NYSE");
+        whole_shares.fractionable = false;
+        let assets = vec![asset("IBM", "NYSE"), asset("AAPL", "NASDAQ"), hard_to_borrow, whole_shares,
+                          asset("PINK", "OTC")];
+
+        let mut filter = UniverseFilter {
+            exchanges: vec!["nyse".to_string()],
+            ..UniverseFilter::default()
+        };
+        assert_eq!(filter.select(&assets), vec!["HTB", "IBM", "WHOLE"]);
+        filter.require_easy_to_borrow = true;
+        filter.require_fractionable = true;
+        assert_eq!(filter.select(&assets), vec!["IBM"]);
+        filter.exchanges.clear();
+        filter.excluded.insert("AAPL".to_string());
+        assert_eq!(filter.select(&assets), vec!["IBM", "PINK"]);
+    }
+}
@@ -0,0 +1,240 @@
+// volume at time of day. days are lined up by minutes since their extended
+// session open (04:00 in New York) rather than by clock position in UTC, so
+// a reference day on the other side of a DST change still compares like for
+// like.
+use crate::alpaca::{Bar, Calendar};
+use crate::session;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+// what to do with a reference day whose hours differ from the analysis
+// day's, e.g. the 13:00 close the day after thanksgiving
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShortSessions {
+    // compare days with different hours only up to the earlier closing
+    // bell, whichever of the two closed early
+    #[default]
+    Exclude,
+    // stretch or squeeze each reference day's regular session to line up
+    // with the analysis day's, so e.g. 14:00 on a full day is compared with
+    // the same share of a half day's session
+    Normalize,
+}
+
+impl fmt::Display for ShortSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShortSessions::Exclude => write!(f, "exclude"),
+            ShortSessions::Normalize => write!(f, "normalize"),
+        }
+    }
+}
+
+impl FromStr for ShortSessions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ShortSessions, String> {
+        match s.to_lowercase().as_str() {
+            "exclude" => Ok(ShortSessions::Exclude),
+            "normalize" => Ok(ShortSessions::Normalize),
+            _ => Err(format!("unknown short session handling {}, expected exclude or normalize", s)),
+        }
+    }
+}
+
+// a day's opening and closing bells and extended close in minutes since its
+// extended open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SessionWindow {
+    open: i64,
+    close: i64,
+    session_close: i64,
+}
+
+fn session_window(day: &Calendar) -> Option<SessionWindow> {
+    let session_open = session::new_york_time(&day.date, &day.session_open)?;
+    let minutes = |time: &str| -> Option<i64> {
+        Some(session::new_york_time(&day.date, time)?.signed_duration_since(session_open).num_minutes())
+    };
+    Some(SessionWindow {
+        open: minutes(&day.open)?,
+        close: minutes(&day.close)?,
+        session_close: minutes(&day.session_close)?,
+    })
+}
+
+// whole minutes from `day`'s session open to `time`, negative before it.
+// None when the calendar entry can't be read.
+pub fn minutes_into_session(day: &Calendar, time: DateTime<Utc>) -> Option<i64> {
+    let session_open = session::new_york_time(&day.date, &day.session_open)?;
+    Some(time.signed_duration_since(session_open).num_minutes())
+}
+
+// volume of `day`'s bars from its session open up to and including the bar
+// `minute` minutes in. the bar for the minute in progress is included, the
+// same as today's volume so far includes it.
+pub fn volume_through_minute(day: &Calendar, bars: &[Bar], minute: i64) -> Option<u64> {
+    let session_open = session::new_york_time(&day.date, &day.session_open)?;
+    Some(bars.iter()
+        .filter(|bar| bar.t.signed_duration_since(session_open).num_minutes() <= minute)
+        .map(|bar| bar.v)
+        .sum())
+}
+
+// the minute on `reference` to compare with `minute` on `analysis`, read
+// from each day's own hours. None when `policy` leaves the day out.
+pub fn reference_minute(analysis: &Calendar, reference: &Calendar, minute: i64, policy: ShortSessions) -> Option<i64> {
+    let today = session_window(analysis)?;
+    let other = session_window(reference)?;
+    if today == other || minute < today.open {
+        return Some(minute);
+    }
+    match policy {
+        ShortSessions::Exclude => {
+            // a full reference day keeps trading its regular session past a
+            // half day's close, so it's left out then just like a half day is
+            // left out past its own close
+            let differs = other.close != today.close || other.session_close != today.session_close;
+            (!differs || minute <= today.close.min(other.close)).then_some(minute)
+        },
+        ShortSessions::Normalize => {
+            if minute >= today.session_close {
+                // the analysis day is over, so is the reference day
+                return Some(minute.max(other.session_close));
+            }
+            if minute < today.close {
+                let regular = today.close - today.open;
+                return Some(other.open + (minute - today.open) * (other.close - other.open) / regular);
+            }
+            // after hours line up from each day's closing bell
+            Some((other.close + minute - today.close).min(other.session_close))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{minutes_into_session, reference_minute, volume_through_minute, ShortSessions};
+    use crate::test_support::{bar, day, half_day, utc};
+
+    #[test]
+    fn minutes_follow_new_york_time() {
+        assert_eq!(minutes_into_session(&day("2024-03-08"), utc("2024-03-08T09:30:00Z")), Some(30));
+        assert_eq!(minutes_into_session(&day("2024-03-11"), utc("2024-03-11T08:30:00Z")), Some(30));
+        assert_eq!(minutes_into_session(&day("2024-03-11"), utc("2024-03-11T07:59:00Z")), Some(-1));
+        assert_eq!(minutes_into_session(&day("2024-03-11"), utc("2024-03-11T08:00:59Z")), Some(0));
+    }
+
+    #[test]
+    fn reference_day_before_spring_forward() {
+        // friday 2024-03-08 is EST, the session opens at 09:00Z. stamped in
+        // UTC these bars are an hour later than monday's would be.
+        let friday = day("2024-03-08");
+        let bars = vec![
+            bar("2024-03-08T09:00:00Z", 100),
+            bar("2024-03-08T09:15:00Z", 200),
+            bar("2024-03-08T09:30:00Z", 300),
+            bar("2024-03-08T09:31:00Z", 400),
+        ];
+        // monday 2024-03-11 is EDT, 04:30 in New York is 08:30Z
+        let minute = minutes_into_session(&day("2024-03-11"), utc("2024-03-11T08:30:20Z")).unwrap();
+        assert_eq!(minute, 30);
+        assert_eq!(volume_through_minute(&friday, &bars, minute), Some(600));
+    }
+
+    #[test]
+    fn reference_day_before_fall_back() {
+        // friday 2023-11-03 is EDT, the session opens at 08:00Z
+        let friday = day("2023-11-03");
+        let bars = vec![
+            bar("2023-11-03T08:00:00Z", 100),
+            bar("2023-11-03T08:59:00Z", 200),
+            bar("2023-11-03T09:00:00Z", 300),
+            bar("2023-11-03T13:30:00Z", 400),
+        ];
+        // monday 2023-11-06 is EST, 04:59 in New York is 09:59Z
+        let minute = minutes_into_session(&day("2023-11-06"), utc("2023-11-06T09:59:00Z")).unwrap();
+        assert_eq!(minute, 59);
+        assert_eq!(volume_through_minute(&friday, &bars, minute), Some(300));
+        // 09:30 in New York, the open, is 13:30Z on friday and 14:30Z on monday
+        let minute = minutes_into_session(&day("2023-11-06"), utc("2023-11-06T14:30:00Z")).unwrap();
+        assert_eq!(volume_through_minute(&friday, &bars, minute), Some(1000));
+    }
+
+    #[test]
+    fn nothing_before_the_open_and_everything_after_the_close() {
+        let monday = day("2024-03-11");
+        let bars = vec![bar("2024-03-11T08:00:00Z", 100), bar("2024-03-11T23:59:00Z", 200)];
+        assert_eq!(volume_through_minute(&monday, &bars, -1), Some(0));
+        assert_eq!(volume_through_minute(&monday, &bars, 0), Some(100));
+        assert_eq!(volume_through_minute(&monday, &bars, i64::MAX), Some(300));
+    }
+
+    #[test]
+    fn matching_days_compare_minute_for_minute() {
+        for policy in [ShortSessions::Exclude, ShortSessions::Normalize] {
+            assert_eq!(reference_minute(&day("2023-11-27"), &day("2023-11-22"), 600, policy), Some(600));
+            // before the opening bell every day is the same
+            assert_eq!(reference_minute(&day("2023-11-27"), &half_day("2023-11-24"), 120, policy), Some(120));
+        }
+    }
+
+    #[test]
+    fn exclude_drops_a_half_day_after_its_close() {
+        let monday = day("2023-11-27");
+        let friday = half_day("2023-11-24");
+        // 12:00 in New York is 480 minutes in, the half day was still trading
+        assert_eq!(reference_minute(&monday, &friday, 480, ShortSessions::Exclude), Some(480));
+        assert_eq!(reference_minute(&monday, &friday, 540, ShortSessions::Exclude), Some(540));
+        // 14:00 is after its 13:00 close
+        assert_eq!(reference_minute(&monday, &friday, 600, ShortSessions::Exclude), None);
+        assert_eq!(reference_minute(&monday, &friday, 900, ShortSessions::Exclude), None);
+    }
+
+    #[test]
+    fn normalize_lines_up_the_share_of_the_session() {
+        let monday = day("2023-11-27");
+        let friday = half_day("2023-11-24");
+        // 14:00 is 270 of 390 regular minutes in, the same share of the half
+        // day's 210 minutes is 145 minutes after its 09:30 open, 11:55
+        assert_eq!(reference_minute(&monday, &friday, 600, ShortSessions::Normalize), Some(475));
+        // the bells line up
+        assert_eq!(reference_minute(&monday, &friday, 330, ShortSessions::Normalize), Some(330));
+        assert_eq!(reference_minute(&monday, &friday, 720, ShortSessions::Normalize), Some(540));
+        // an hour after the close is an hour after the close
+        assert_eq!(reference_minute(&monday, &friday, 780, ShortSessions::Normalize), Some(600));
+        assert_eq!(reference_minute(&monday, &friday, 959, ShortSessions::Normalize), Some(779));
+        // and once the analysis day is over so is the reference day
+        assert_eq!(reference_minute(&monday, &friday, 2000, ShortSessions::Normalize), Some(2000));
+    }
+
+    #[test]
+    fn normalize_works_for_a_half_day_analyzed_against_full_days() {
+        let friday = half_day("2023-11-24");
+        let wednesday = day("2023-11-22");
+        // the half day's 13:00 close lines up with the full day's 16:00 close
+        assert_eq!(reference_minute(&friday, &wednesday, 540, ShortSessions::Normalize), Some(720));
+    }
+
+    #[test]
+    fn exclude_drops_full_days_after_a_half_days_close() {
+        let friday = half_day("2023-11-24");
+        let wednesday = day("2023-11-22");
+        // up to friday's 13:00 close both days are in their regular session
+        assert_eq!(reference_minute(&friday, &wednesday, 300, ShortSessions::Exclude), Some(300));
+        assert_eq!(reference_minute(&friday, &wednesday, 540, ShortSessions::Exclude), Some(540));
+        // after it friday is in after-hours while wednesday still trades
+        assert_eq!(reference_minute(&friday, &wednesday, 541, ShortSessions::Exclude), None);
+        assert_eq!(reference_minute(&friday, &wednesday, 780, ShortSessions::Exclude), None);
+        // and once friday is over
+        assert_eq!(reference_minute(&friday, &wednesday, i64::MAX, ShortSessions::Exclude), None);
+    }
+
+    #[test]
+    fn short_sessions_parse() {
+        assert_eq!("normalize".parse::<ShortSessions>().unwrap(), ShortSessions::Normalize);
+        assert_eq!("Exclude".parse::<ShortSessions>().unwrap(), ShortSessions::Exclude);
+        assert!("skip".parse::<ShortSessions>().is_err());
+    }
+}